@@ -0,0 +1,97 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use r2d2::{ManageConnection, Pool};
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+use std::{error, fmt, thread};
+
+#[derive(Debug)]
+struct Error;
+
+impl fmt::Display for Error {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("error")
+    }
+}
+
+impl error::Error for Error {}
+
+struct NopManager;
+
+impl ManageConnection for NopManager {
+    type Connection = ();
+    type Error = Error;
+
+    fn connect(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn is_valid(&self, _: &mut ()) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn has_broken(&self, _: &mut ()) -> bool {
+        false
+    }
+}
+
+// Measures checkout throughput with a pool large enough that threads never
+// wait on each other for a connection, only on the pool's internal locking.
+//
+// Pools only shard their idle connections if `idle_shards` is set, so the
+// `1_shards` cases are what users get by default. To compare against the
+// design before sharding, which took a single pool-wide lock on every
+// checkout, run this with the `idle_shards` call removed at commit 9c6e761.
+fn contended_checkout(c: &mut Criterion) {
+    let cpus = thread::available_parallelism().map_or(4, |n| n.get()) as u32;
+    let mut shard_counts = vec![1];
+    if cpus > 1 {
+        shard_counts.push(cpus);
+    }
+
+    let mut group = c.benchmark_group("contended_checkout");
+    for &threads in &[1, 4, 16, 64] {
+        for &shards in &shard_counts {
+            let pool = Pool::builder()
+                .max_size(200)
+                .idle_shards(shards)
+                .test_on_check_out(false)
+                .build(NopManager)
+                .unwrap();
+
+            group.throughput(Throughput::Elements(threads));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{}_shards", shards), threads),
+                &threads,
+                |b, &threads| b.iter_custom(|iters| run(&pool, threads as usize, iters)),
+            );
+        }
+    }
+    group.finish();
+}
+
+// Each of `threads` threads checks a connection out and back in `iters` times.
+fn run(pool: &Pool<NopManager>, threads: usize, iters: u64) -> Duration {
+    let barrier = Arc::new(Barrier::new(threads + 1));
+    let handles = (0..threads)
+        .map(|_| {
+            let pool = pool.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..iters {
+                    drop(pool.get().unwrap());
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    barrier.wait();
+    let start = Instant::now();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+criterion_group!(benches, contended_checkout);
+criterion_main!(benches);
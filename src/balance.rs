@@ -0,0 +1,469 @@
+//! Load balancing across multiple endpoints.
+//!
+//! A `BalancingManager` wraps several `ManageConnection` implementations, for
+//! example one per read replica, so that a single `Pool` can own connections
+//! to all of them.
+//!
+//! ```rust,ignore
+//! use r2d2::balance::{BalancingManager, Strategy};
+//!
+//! let manager = BalancingManager::new(
+//!     vec![
+//!         FooConnectionManager::new("replica1:1234"),
+//!         FooConnectionManager::new("replica2:1234"),
+//!     ],
+//!     Strategy::LeastConnections,
+//! );
+//! let pool = r2d2::Pool::new(manager).unwrap();
+//!
+//! for state in pool.endpoint_states() {
+//!     println!("{:?}", state);
+//! }
+//! ```
+use parking_lot::Mutex;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::future::connect_first;
+use crate::{BoxFuture, ManageConnection, Pool, State};
+
+/// The strategy used by a `BalancingManager` to pick the endpoint for a new
+/// connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Cycle through the endpoints in order.
+    RoundRobin,
+    /// Pick the endpoint with the fewest open connections.
+    LeastConnections,
+    /// Distribute connections in proportion to the given per-endpoint weights.
+    Weighted(Vec<u32>),
+}
+
+#[derive(Default)]
+struct EndpointStats {
+    connections: AtomicU32,
+    consecutive_failures: AtomicU32,
+    unhealthy_since: Mutex<Option<Instant>>,
+}
+
+impl EndpointStats {
+    fn record_success(&self) {
+        self.consecutive_failures.store(0, Ordering::SeqCst);
+        *self.unhealthy_since.lock() = None;
+    }
+
+    fn record_failure(&self, failure_threshold: u32) {
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::SeqCst) + 1;
+        if failures >= failure_threshold {
+            *self.unhealthy_since.lock() = Some(Instant::now());
+        }
+    }
+}
+
+struct Endpoint<M> {
+    manager: M,
+    stats: Arc<EndpointStats>,
+}
+
+/// A `ManageConnection` which distributes connections across several inner
+/// managers.
+///
+/// Endpoints which fail `failure_threshold` connection attempts or validity
+/// checks in a row are considered unhealthy, and are not picked for new
+/// connections until `retry_interval` has passed. If every endpoint is
+/// unhealthy, all of them are tried.
+pub struct BalancingManager<M> {
+    endpoints: Vec<Endpoint<M>>,
+    strategy: Strategy,
+    failure_threshold: u32,
+    retry_interval: Duration,
+    next: AtomicUsize,
+    // smooth weighted round robin state
+    current_weights: Mutex<Vec<i64>>,
+}
+
+impl<M> fmt::Debug for BalancingManager<M>
+where
+    M: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let managers = self
+            .endpoints
+            .iter()
+            .map(|e| &e.manager)
+            .collect::<Vec<_>>();
+        fmt.debug_struct("BalancingManager")
+            .field("managers", &managers)
+            .field("strategy", &self.strategy)
+            .field("failure_threshold", &self.failure_threshold)
+            .field("retry_interval", &self.retry_interval)
+            .finish()
+    }
+}
+
+impl<M> BalancingManager<M>
+where
+    M: ManageConnection,
+{
+    /// Creates a new `BalancingManager` over the provided managers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `managers` is empty, or if the strategy is `Weighted` and
+    /// does not have exactly one weight per manager, or all weights are 0.
+    pub fn new(managers: Vec<M>, strategy: Strategy) -> BalancingManager<M> {
+        assert!(!managers.is_empty(), "managers must not be empty");
+        if let Strategy::Weighted(ref weights) = strategy {
+            assert_eq!(
+                weights.len(),
+                managers.len(),
+                "there must be one weight per manager"
+            );
+            assert!(
+                weights.iter().any(|w| *w > 0),
+                "at least one weight must be positive"
+            );
+        }
+
+        let current_weights = vec![0; managers.len()];
+        let endpoints = managers
+            .into_iter()
+            .map(|manager| Endpoint {
+                manager,
+                stats: Arc::new(EndpointStats::default()),
+            })
+            .collect();
+
+        BalancingManager {
+            endpoints,
+            strategy,
+            failure_threshold: 3,
+            retry_interval: Duration::from_secs(10),
+            next: AtomicUsize::new(0),
+            current_weights: Mutex::new(current_weights),
+        }
+    }
+
+    /// Sets the number of consecutive failures after which an endpoint is
+    /// considered unhealthy.
+    ///
+    /// Defaults to 3.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `failure_threshold` is 0.
+    pub fn failure_threshold(mut self, failure_threshold: u32) -> BalancingManager<M> {
+        assert!(failure_threshold > 0, "failure_threshold must be positive");
+        self.failure_threshold = failure_threshold;
+        self
+    }
+
+    /// Sets how long an unhealthy endpoint is skipped before it is tried
+    /// again.
+    ///
+    /// Defaults to 10 seconds.
+    pub fn retry_interval(mut self, retry_interval: Duration) -> BalancingManager<M> {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    /// Returns the number of endpoints.
+    pub fn endpoints(&self) -> usize {
+        self.endpoints.len()
+    }
+
+    /// Returns the manager for an endpoint.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `endpoint` is out of bounds.
+    pub fn manager(&self, endpoint: usize) -> &M {
+        &self.endpoints[endpoint].manager
+    }
+
+    /// Returns the number of open connections to an endpoint.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `endpoint` is out of bounds.
+    pub fn connections(&self, endpoint: usize) -> u32 {
+        self.endpoints[endpoint]
+            .stats
+            .connections
+            .load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of connection attempts or validity checks against
+    /// an endpoint that have failed in a row.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `endpoint` is out of bounds.
+    pub fn consecutive_failures(&self, endpoint: usize) -> u32 {
+        self.endpoints[endpoint]
+            .stats
+            .consecutive_failures
+            .load(Ordering::SeqCst)
+    }
+
+    /// Determines if an endpoint is currently eligible for new connections.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `endpoint` is out of bounds.
+    pub fn is_healthy(&self, endpoint: usize) -> bool {
+        match *self.endpoints[endpoint].stats.unhealthy_since.lock() {
+            Some(since) => since.elapsed() >= self.retry_interval,
+            None => true,
+        }
+    }
+
+    // Returns the endpoints to try, in order.
+    fn candidates(&self) -> Vec<usize> {
+        let mut healthy = (0..self.endpoints.len())
+            .filter(|&i| self.is_healthy(i))
+            .collect::<Vec<_>>();
+        if healthy.is_empty() {
+            healthy = (0..self.endpoints.len()).collect();
+        }
+
+        let first = match self.strategy {
+            Strategy::RoundRobin => {
+                let next = self.next.fetch_add(1, Ordering::Relaxed);
+                healthy[next % healthy.len()]
+            }
+            Strategy::LeastConnections => {
+                // rotate the starting point so ties are spread out
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+                let n = healthy.len();
+                (0..n)
+                    .map(|i| healthy[(i + offset) % n])
+                    .min_by_key(|&i| self.connections(i))
+                    .unwrap()
+            }
+            Strategy::Weighted(ref weights) => {
+                let mut current = self.current_weights.lock();
+                let total = healthy.iter().map(|&i| i64::from(weights[i])).sum::<i64>();
+                for &i in &healthy {
+                    current[i] += i64::from(weights[i]);
+                }
+                let best = *healthy.iter().max_by_key(|&&i| current[i]).unwrap();
+                current[best] -= total;
+                best
+            }
+        };
+
+        let mut candidates = vec![first];
+        candidates.extend(healthy.into_iter().filter(|&i| i != first));
+        candidates
+    }
+}
+
+impl<M> ManageConnection for BalancingManager<M>
+where
+    M: ManageConnection,
+{
+    type Connection = BalancedConnection<M::Connection>;
+    type Error = M::Error;
+
+    fn connect(&self) -> Result<BalancedConnection<M::Connection>, M::Error> {
+        self.connect_with(|manager| manager.connect())
+    }
+
+    fn connect_with_deadline(
+        &self,
+        deadline: Instant,
+    ) -> Result<BalancedConnection<M::Connection>, M::Error> {
+        self.connect_with(|manager| manager.connect_with_deadline(deadline))
+    }
+
+    fn is_valid(&self, conn: &mut BalancedConnection<M::Connection>) -> Result<(), M::Error> {
+        self.validate_with(conn, |manager, conn| manager.is_valid(conn))
+    }
+
+    fn is_valid_with_deadline(
+        &self,
+        conn: &mut BalancedConnection<M::Connection>,
+        deadline: Instant,
+    ) -> Result<(), M::Error> {
+        self.validate_with(conn, |manager, conn| {
+            manager.is_valid_with_deadline(conn, deadline)
+        })
+    }
+
+    fn has_broken(&self, conn: &mut BalancedConnection<M::Connection>) -> bool {
+        self.endpoints[conn.endpoint]
+            .manager
+            .has_broken(&mut conn.conn)
+    }
+
+    fn connect_async(
+        &self,
+    ) -> Option<BoxFuture<'static, Result<BalancedConnection<M::Connection>, M::Error>>> {
+        // Every endpoint's future is created before picking the candidates so
+        // that the strategy doesn't advance twice when falling back to
+        // `connect`.
+        let mut futures = self
+            .endpoints
+            .iter()
+            .map(|endpoint| endpoint.manager.connect_async().map(Some))
+            .collect::<Option<Vec<_>>>()?;
+        let attempts = self
+            .candidates()
+            .into_iter()
+            .map(|endpoint| {
+                let future = futures[endpoint].take().unwrap();
+                ((endpoint, self.endpoints[endpoint].stats.clone()), future)
+            })
+            .collect::<Vec<_>>();
+        let failure_threshold = self.failure_threshold;
+
+        Some(Box::pin(async move {
+            let ((endpoint, stats), conn) = connect_first(attempts, |(_, stats)| {
+                stats.record_failure(failure_threshold)
+            })
+            .await?;
+            stats.record_success();
+            Ok(BalancedConnection::new(conn, endpoint, stats))
+        }))
+    }
+}
+
+impl<M> BalancingManager<M>
+where
+    M: ManageConnection,
+{
+    fn connect_with<F>(&self, connect: F) -> Result<BalancedConnection<M::Connection>, M::Error>
+    where
+        F: Fn(&M) -> Result<M::Connection, M::Error>,
+    {
+        let mut last_error = None;
+
+        for endpoint in self.candidates() {
+            let stats = &self.endpoints[endpoint].stats;
+            match connect(&self.endpoints[endpoint].manager) {
+                Ok(conn) => {
+                    stats.record_success();
+                    return Ok(BalancedConnection::new(conn, endpoint, stats.clone()));
+                }
+                Err(e) => {
+                    stats.record_failure(self.failure_threshold);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        Err(last_error.unwrap())
+    }
+
+    fn validate_with<F>(
+        &self,
+        conn: &mut BalancedConnection<M::Connection>,
+        is_valid: F,
+    ) -> Result<(), M::Error>
+    where
+        F: FnOnce(&M, &mut M::Connection) -> Result<(), M::Error>,
+    {
+        let endpoint = &self.endpoints[conn.endpoint];
+        match is_valid(&endpoint.manager, &mut conn.conn) {
+            Ok(()) => {
+                endpoint.stats.record_success();
+                Ok(())
+            }
+            Err(e) => {
+                endpoint.stats.record_failure(self.failure_threshold);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A connection created by a `BalancingManager`.
+///
+/// It dereferences to the inner manager's connection type.
+pub struct BalancedConnection<C> {
+    conn: C,
+    endpoint: usize,
+    stats: Arc<EndpointStats>,
+}
+
+impl<C> BalancedConnection<C> {
+    fn new(conn: C, endpoint: usize, stats: Arc<EndpointStats>) -> BalancedConnection<C> {
+        stats.connections.fetch_add(1, Ordering::SeqCst);
+        BalancedConnection {
+            conn,
+            endpoint,
+            stats,
+        }
+    }
+}
+
+impl<C> Drop for BalancedConnection<C> {
+    fn drop(&mut self) {
+        self.stats.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<C> fmt::Debug for BalancedConnection<C>
+where
+    C: fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("BalancedConnection")
+            .field("conn", &self.conn)
+            .field("endpoint", &self.endpoint)
+            .finish()
+    }
+}
+
+impl<C> Deref for BalancedConnection<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.conn
+    }
+}
+
+impl<C> DerefMut for BalancedConnection<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+}
+
+impl<C> BalancedConnection<C> {
+    /// Returns the index of the endpoint this connection was made to.
+    pub fn endpoint(this: &Self) -> usize {
+        this.endpoint
+    }
+}
+
+impl<M> Pool<BalancingManager<M>>
+where
+    M: ManageConnection,
+{
+    /// Returns information about the state of the pool for each endpoint, in
+    /// the order the managers were provided.
+    pub fn endpoint_states(&self) -> Vec<State> {
+        let manager = &self.0.manager;
+
+        let mut idle = vec![0; manager.endpoints()];
+        self.0
+            .idle
+            .for_each(|conn| idle[conn.conn.conn.endpoint] += 1);
+        if let Some(ref cache) = self.0.cache {
+            cache.for_each(|conn| idle[conn.conn.conn.endpoint] += 1);
+        }
+
+        idle.into_iter()
+            .enumerate()
+            .map(|(endpoint, idle_connections)| State {
+                connections: manager.connections(endpoint),
+                idle_connections,
+                _p: (),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,150 @@
+use parking_lot::Mutex;
+use std::any::Any;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use crate::IdleConn;
+
+static NEXT_CACHE_ID: AtomicU64 = AtomicU64::new(0);
+
+type Slot<C> = Mutex<Option<IdleConn<C>>>;
+
+thread_local! {
+    // this thread's slot in each pool it has used, keyed by cache ID
+    static SLOTS: RefCell<Vec<(u64, Arc<dyn Any + Send + Sync>)>> = RefCell::new(vec![]);
+}
+
+/// Idle connections cached by the threads which last returned them, for
+/// `Builder::thread_cache`.
+///
+/// Each thread has a slot holding at most one connection, which it can take
+/// back without touching the shared idle list. The pool also keeps hold of
+/// every slot so that it can reap cached connections and hand them to other
+/// threads, and so that connections cached by threads which have since exited
+/// aren't lost.
+pub struct ThreadCache<C> {
+    id: u64,
+    slots: Mutex<Vec<Arc<Slot<C>>>>,
+    len: AtomicUsize,
+}
+
+impl<C> ThreadCache<C>
+where
+    C: Send + 'static,
+{
+    pub fn new() -> ThreadCache<C> {
+        ThreadCache {
+            id: NEXT_CACHE_ID.fetch_add(1, Ordering::Relaxed),
+            slots: Mutex::new(vec![]),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of cached connections.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    // Returns `None` if the thread-local has already been destroyed because
+    // the thread is exiting.
+    fn slot(&self) -> Option<Arc<Slot<C>>> {
+        SLOTS
+            .try_with(|slots| {
+                let mut slots = slots.borrow_mut();
+                if let Some((_, slot)) = slots.iter().find(|(id, _)| *id == self.id) {
+                    return slot.clone().downcast().unwrap();
+                }
+
+                // forget about the slots of pools which have been dropped
+                slots.retain(|(_, slot)| Arc::strong_count(slot) > 1);
+
+                let slot = Arc::new(Mutex::new(None));
+                self.slots.lock().push(slot.clone());
+                slots.push((self.id, slot.clone()));
+                slot
+            })
+            .ok()
+    }
+
+    /// Takes the connection cached by the current thread, if there is one.
+    pub fn take(&self) -> Option<IdleConn<C>> {
+        let conn = self.slot()?.lock().take();
+        if conn.is_some() {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        }
+        conn
+    }
+
+    /// Caches a connection for the current thread, handing it back if the
+    /// thread already has one cached or `cache` returns false.
+    ///
+    /// `cache` is called with the slot locked, so it can't race with `drain`.
+    pub fn put<F>(&self, conn: IdleConn<C>, cache: F) -> Result<(), IdleConn<C>>
+    where
+        F: FnOnce() -> bool,
+    {
+        let slot = match self.slot() {
+            Some(slot) => slot,
+            None => return Err(conn),
+        };
+        let mut slot = slot.lock();
+        if slot.is_some() || !cache() {
+            return Err(conn);
+        }
+        *slot = Some(conn);
+        self.len.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Calls the closure on each cached connection.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&IdleConn<C>),
+    {
+        for slot in self.slots.lock().iter() {
+            if let Some(ref conn) = *slot.lock() {
+                f(conn);
+            }
+        }
+    }
+
+    /// Removes and returns all cached connections.
+    pub fn drain(&self) -> Vec<IdleConn<C>> {
+        self.drain_slots(|_, _| true)
+    }
+
+    /// Removes and returns the cached connections for which the predicate
+    /// returns true.
+    pub fn drain_where<F>(&self, mut f: F) -> Vec<IdleConn<C>>
+    where
+        F: FnMut(&IdleConn<C>) -> bool,
+    {
+        self.drain_slots(|conn, _| f(conn))
+    }
+
+    /// Removes and returns the connections cached by threads which have
+    /// exited.
+    pub fn drain_abandoned(&self) -> Vec<IdleConn<C>> {
+        self.drain_slots(|_, abandoned| abandoned)
+    }
+
+    // Also forgets about the empty slots of threads which have exited.
+    fn drain_slots<F>(&self, mut f: F) -> Vec<IdleConn<C>>
+    where
+        F: FnMut(&IdleConn<C>, bool) -> bool,
+    {
+        let mut drained = vec![];
+        self.slots.lock().retain(|slot| {
+            // the exited thread's reference went away with its thread-locals
+            let abandoned = Arc::strong_count(slot) == 1;
+            let mut slot = slot.lock();
+            if slot.as_ref().map_or(false, |conn| f(conn, abandoned)) {
+                drained.push(slot.take().unwrap());
+            }
+            !abandoned || slot.is_some()
+        });
+        self.len.fetch_sub(drained.len(), Ordering::SeqCst);
+        drained
+    }
+}
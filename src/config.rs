@@ -1,9 +1,13 @@
+use parking_lot::Mutex;
 use scheduled_thread_pool::ScheduledThreadPool;
+use std::convert::TryFrom;
 use std::fmt;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
+use crate::keyed::ConnectionLimit;
 use crate::{
     CustomizeConnection, Error, HandleError, HandleEvent, LoggingErrorHandler, ManageConnection,
     NopConnectionCustomizer, NopEventHandler, Pool,
@@ -18,13 +22,22 @@ where
     min_idle: Option<u32>,
     test_on_check_out: bool,
     max_lifetime: Option<Duration>,
+    max_uses: Option<u64>,
     idle_timeout: Option<Duration>,
     connection_timeout: Duration,
+    connect_timeout: Option<Duration>,
+    validation_timeout: Option<Duration>,
     error_handler: Box<dyn HandleError<M::Error>>,
     connection_customizer: Box<dyn CustomizeConnection<M::Connection, M::Error>>,
     event_handler: Box<dyn HandleEvent>,
     thread_pool: Option<Arc<ScheduledThreadPool>>,
+    synchronous: bool,
+    automatic_reaping: bool,
+    idle_shards: u32,
+    thread_cache: bool,
+    detect_reentrant_checkout: bool,
     reaper_rate: Duration,
+    connection_limit: Option<Arc<ConnectionLimit>>,
     _p: PhantomData<M>,
 }
 
@@ -38,11 +51,19 @@ where
             .field("min_idle", &self.min_idle)
             .field("test_on_check_out", &self.test_on_check_out)
             .field("max_lifetime", &self.max_lifetime)
+            .field("max_uses", &self.max_uses)
             .field("idle_timeout", &self.idle_timeout)
             .field("connection_timeout", &self.connection_timeout)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("validation_timeout", &self.validation_timeout)
             .field("error_handler", &self.error_handler)
             .field("event_handler", &self.event_handler)
             .field("connection_customizer", &self.connection_customizer)
+            .field("synchronous", &self.synchronous)
+            .field("automatic_reaping", &self.automatic_reaping)
+            .field("idle_shards", &self.idle_shards)
+            .field("thread_cache", &self.thread_cache)
+            .field("detect_reentrant_checkout", &self.detect_reentrant_checkout)
             .finish()
     }
 }
@@ -58,12 +79,21 @@ where
             test_on_check_out: true,
             idle_timeout: Some(Duration::from_secs(10 * 60)),
             max_lifetime: Some(Duration::from_secs(30 * 60)),
+            max_uses: None,
             connection_timeout: Duration::from_secs(30),
+            connect_timeout: None,
+            validation_timeout: None,
             error_handler: Box::new(LoggingErrorHandler),
             event_handler: Box::new(NopEventHandler),
             connection_customizer: Box::new(NopConnectionCustomizer),
             thread_pool: None,
+            synchronous: false,
+            automatic_reaping: true,
+            idle_shards: 1,
+            thread_cache: false,
+            detect_reentrant_checkout: false,
             reaper_rate: Duration::from_secs(30),
+            connection_limit: None,
             _p: PhantomData,
         }
     }
@@ -113,6 +143,67 @@ where
         self
     }
 
+    /// If true, the pool will not use any background threads.
+    ///
+    /// New connections are instead established on the thread calling
+    /// `Pool::get` when the pool has capacity for them, and expired
+    /// connections are reaped opportunistically as connections are checked
+    /// out and returned. The `min_idle` connections are only established when
+    /// the pool is built. Any thread pool set via `thread_pool` is ignored.
+    ///
+    /// Defaults to false.
+    pub fn synchronous(mut self, synchronous: bool) -> Builder<M> {
+        self.synchronous = synchronous;
+        self
+    }
+
+    /// If false, the pool will not reap connections which have exceeded their
+    /// idle timeout or maximum lifetime on its own.
+    ///
+    /// Reaping is then left to calls to `Pool::run_maintenance`, allowing it
+    /// to be driven by an external scheduler.
+    ///
+    /// Defaults to true.
+    pub fn automatic_reaping(mut self, automatic_reaping: bool) -> Builder<M> {
+        self.automatic_reaping = automatic_reaping;
+        self
+    }
+
+    /// Sets the number of shards the pool's idle connections are split across.
+    ///
+    /// Each thread returns connections to and takes connections from its own
+    /// shard, only falling back to the others when that shard is empty. On
+    /// machines with many cores, using more shards reduces contention between
+    /// threads checking connections in and out, at the cost of connections no
+    /// longer being reused in strictly most-recently-used order.
+    ///
+    /// Defaults to 1.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idle_shards` is 0.
+    pub fn idle_shards(mut self, idle_shards: u32) -> Builder<M> {
+        assert!(idle_shards > 0, "idle_shards must be positive");
+        self.idle_shards = idle_shards;
+        self
+    }
+
+    /// If true, each thread keeps the last connection it returned to the pool
+    /// to itself, getting the same connection back from its next call to
+    /// `Pool::get` without touching the shared idle list.
+    ///
+    /// This suits thread-per-core servers where contention on the idle list
+    /// dominates. Cached connections still count against `max_size` and are
+    /// still reaped, and they are handed over to other threads whenever those
+    /// find no other idle connections, or to make room for other keys in a
+    /// `KeyedPool`.
+    ///
+    /// Defaults to false.
+    pub fn thread_cache(mut self, thread_cache: bool) -> Builder<M> {
+        self.thread_cache = thread_cache;
+        self
+    }
+
     /// If true, the health of a connection will be verified via a call to
     /// `ConnectionManager::is_valid` before it is checked out of the pool.
     ///
@@ -141,10 +232,29 @@ where
         self
     }
 
+    /// Sets the maximum number of times a connection may be checked out of
+    /// the pool.
+    ///
+    /// If set, connections will be closed when they are returned to the pool
+    /// after their final use.
+    ///
+    /// Defaults to `None`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_uses` is 0.
+    pub fn max_uses(mut self, max_uses: Option<u64>) -> Builder<M> {
+        assert_ne!(max_uses, Some(0), "max_uses must be positive");
+        self.max_uses = max_uses;
+        self
+    }
+
     /// Sets the idle timeout used by the pool.
     ///
     /// If set, connections will be closed after sitting idle for at most 30
-    /// seconds beyond this duration.
+    /// seconds beyond this duration. Connections are not closed for being idle
+    /// if that would leave fewer than `min_idle` idle connections in the pool,
+    /// so with the default `min_idle`, which is `max_size`, none are.
     ///
     /// Defaults to 10 minutes.
     ///
@@ -176,6 +286,56 @@ where
         self
     }
 
+    /// Sets the maximum amount of time a single attempt to establish a
+    /// connection may take.
+    ///
+    /// Attempts made in the background which take longer are given up on and
+    /// retried, counting as a failed attempt. A thread blocked in
+    /// `ManageConnection::connect` can't be interrupted, so managers should
+    /// also respect the deadline passed to
+    /// `ManageConnection::connect_with_deadline`, which is derived from this.
+    ///
+    /// Defaults to `None`, in which case attempts are not timed out and the
+    /// manager is given the connection timeout as its deadline.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `connect_timeout` is the zero `Duration`.
+    pub fn connect_timeout(mut self, connect_timeout: Option<Duration>) -> Builder<M> {
+        assert_ne!(
+            connect_timeout,
+            Some(Duration::from_secs(0)),
+            "connect_timeout must be positive"
+        );
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Sets the maximum amount of time validating a connection via
+    /// `ManageConnection::is_valid` may take.
+    ///
+    /// A validation which takes longer is treated as a failure, and the
+    /// connection is discarded. Validation performed by `Pool::get` is
+    /// additionally bounded by the time remaining before it times out. As
+    /// with `connect_timeout`, managers should respect the deadline passed to
+    /// `ManageConnection::is_valid_with_deadline` so that checks are not left
+    /// running past it.
+    ///
+    /// Defaults to `None`, in which case the connection timeout is used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `validation_timeout` is the zero `Duration`.
+    pub fn validation_timeout(mut self, validation_timeout: Option<Duration>) -> Builder<M> {
+        assert_ne!(
+            validation_timeout,
+            Some(Duration::from_secs(0)),
+            "validation_timeout must be positive"
+        );
+        self.validation_timeout = validation_timeout;
+        self
+    }
+
     /// Sets the handler for errors reported in the pool.
     ///
     /// Defaults to the `LoggingErrorHandler`.
@@ -203,6 +363,26 @@ where
         self
     }
 
+    /// If true, the pool will track how many connections each thread has
+    /// checked out, and fail a checkout immediately if the calling thread
+    /// itself holds so many connections that it could never succeed.
+    ///
+    /// Without this, a thread which already holds `max_size` connections and
+    /// asks for another simply waits until the connection timeout. With it,
+    /// the checkout returns an error instead. It adds some overhead to every
+    /// checkout, so it is intended as a debugging aid.
+    ///
+    /// Connections are counted against the thread which checked them out,
+    /// even if they have since been moved to another thread. A thread which
+    /// hands its connections off to others can therefore be refused a
+    /// checkout which would have succeeded once they were returned.
+    ///
+    /// Defaults to false.
+    pub fn detect_reentrant_checkout(mut self, detect_reentrant_checkout: bool) -> Builder<M> {
+        self.detect_reentrant_checkout = detect_reentrant_checkout;
+        self
+    }
+
     // used by tests
     #[allow(dead_code)]
     pub(crate) fn reaper_rate(mut self, reaper_rate: Duration) -> Builder<M> {
@@ -210,6 +390,12 @@ where
         self
     }
 
+    // Only honored when connections are established on the thread pool.
+    pub(crate) fn connection_limit(mut self, connection_limit: Arc<ConnectionLimit>) -> Builder<M> {
+        self.connection_limit = Some(connection_limit);
+        self
+    }
+
     /// Consumes the builder, returning a new, initialized pool.
     ///
     /// It will block until the pool has established its configured minimum
@@ -245,39 +431,65 @@ where
             );
         }
 
-        let thread_pool = match self.thread_pool {
-            Some(thread_pool) => thread_pool,
-            None => Arc::new(ScheduledThreadPool::with_name("r2d2-worker-{}", 3)),
+        #[allow(deprecated)]
+        let thread_pool = if self.synchronous {
+            None
+        } else {
+            match self.thread_pool {
+                Some(thread_pool) => Some(thread_pool),
+                None => Some(Arc::new(ScheduledThreadPool::with_name(
+                    "r2d2-worker-{}",
+                    3,
+                ))),
+            }
         };
 
         let config = Config {
             max_size: self.max_size,
             min_idle: self.min_idle,
-            test_on_check_out: self.test_on_check_out,
-            max_lifetime: self.max_lifetime,
-            idle_timeout: self.idle_timeout,
-            connection_timeout: self.connection_timeout,
+            runtime: RuntimeSettings::new(RuntimeConfig {
+                test_on_check_out: self.test_on_check_out,
+                max_lifetime: self.max_lifetime,
+                idle_timeout: self.idle_timeout,
+                connection_timeout: self.connection_timeout,
+                _p: (),
+            }),
+            max_uses: self.max_uses,
+            connect_timeout: self.connect_timeout,
+            validation_timeout: self.validation_timeout,
             error_handler: self.error_handler,
             event_handler: self.event_handler,
             connection_customizer: self.connection_customizer,
             thread_pool,
+            automatic_reaping: self.automatic_reaping,
+            idle_shards: self.idle_shards,
+            thread_cache: self.thread_cache,
+            detect_reentrant_checkout: self.detect_reentrant_checkout,
+            reaper_rate: self.reaper_rate,
+            connection_limit: self.connection_limit,
         };
 
-        Pool::new_inner(config, manager, self.reaper_rate)
+        Pool::new_inner(config, manager)
     }
 }
 
 pub struct Config<C, E> {
     pub max_size: u32,
     pub min_idle: Option<u32>,
-    pub test_on_check_out: bool,
-    pub max_lifetime: Option<Duration>,
-    pub idle_timeout: Option<Duration>,
-    pub connection_timeout: Duration,
+    pub runtime: RuntimeSettings,
+    pub max_uses: Option<u64>,
+    pub connect_timeout: Option<Duration>,
+    pub validation_timeout: Option<Duration>,
     pub error_handler: Box<dyn HandleError<E>>,
     pub event_handler: Box<dyn HandleEvent>,
     pub connection_customizer: Box<dyn CustomizeConnection<C, E>>,
-    pub thread_pool: Arc<ScheduledThreadPool>,
+    pub thread_pool: Option<Arc<ScheduledThreadPool>>,
+    pub automatic_reaping: bool,
+    pub idle_shards: u32,
+    pub thread_cache: bool,
+    pub detect_reentrant_checkout: bool,
+    pub reaper_rate: Duration,
+    pub connection_limit: Option<Arc<ConnectionLimit>>,
 }
 
 // manual to avoid bounds on C and E
@@ -286,13 +498,146 @@ impl<C, E> fmt::Debug for Config<C, E> {
         fmt.debug_struct("Config")
             .field("max_size", &self.max_size)
             .field("min_idle", &self.min_idle)
-            .field("test_on_check_out", &self.test_on_check_out)
-            .field("max_lifetime", &self.max_lifetime)
-            .field("idle_timeout", &self.idle_timeout)
-            .field("connection_timeout", &self.connection_timeout)
+            .field("runtime", &self.runtime.get())
+            .field("max_uses", &self.max_uses)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("validation_timeout", &self.validation_timeout)
             .field("error_handler", &self.error_handler)
             .field("event_handler", &self.event_handler)
             .field("connection_customizer", &self.connection_customizer)
+            .field("synchronous", &self.thread_pool.is_none())
+            .field("automatic_reaping", &self.automatic_reaping)
+            .field("idle_shards", &self.idle_shards)
+            .field("thread_cache", &self.thread_cache)
+            .field("detect_reentrant_checkout", &self.detect_reentrant_checkout)
+            .finish()
+    }
+}
+
+/// The parts of a pool's configuration which can be changed while it is
+/// running.
+///
+/// See `Pool::reconfigure`.
+#[derive(Clone, Copy)]
+#[allow(clippy::manual_non_exhaustive)]
+pub struct RuntimeConfig {
+    /// See `Builder::connection_timeout`.
+    pub connection_timeout: Duration,
+    /// See `Builder::idle_timeout`.
+    pub idle_timeout: Option<Duration>,
+    /// See `Builder::max_lifetime`.
+    pub max_lifetime: Option<Duration>,
+    /// See `Builder::test_on_check_out`.
+    pub test_on_check_out: bool,
+    _p: (),
+}
+
+impl fmt::Debug for RuntimeConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RuntimeConfig")
+            .field("connection_timeout", &self.connection_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_lifetime", &self.max_lifetime)
+            .field("test_on_check_out", &self.test_on_check_out)
             .finish()
     }
 }
+
+impl RuntimeConfig {
+    pub(crate) fn expires_conns(&self) -> bool {
+        self.max_lifetime.is_some() || self.idle_timeout.is_some()
+    }
+
+    pub(crate) fn validate(&self) {
+        assert!(
+            self.connection_timeout > Duration::from_secs(0),
+            "connection_timeout must be positive"
+        );
+        assert_ne!(
+            self.idle_timeout,
+            Some(Duration::from_secs(0)),
+            "idle_timeout must be positive"
+        );
+        assert_ne!(
+            self.max_lifetime,
+            Some(Duration::from_secs(0)),
+            "max_lifetime must be positive"
+        );
+    }
+}
+
+// A pool's `RuntimeConfig`, kept in atomics so that checkouts can read it
+// without taking a lock. Durations are stored in nanoseconds, with 0 standing
+// in for `None`.
+pub struct RuntimeSettings {
+    connection_timeout: AtomicU64,
+    idle_timeout: AtomicU64,
+    max_lifetime: AtomicU64,
+    test_on_check_out: AtomicBool,
+    // serializes updates
+    update: Mutex<()>,
+}
+
+impl RuntimeSettings {
+    pub fn new(config: RuntimeConfig) -> RuntimeSettings {
+        let settings = RuntimeSettings {
+            connection_timeout: AtomicU64::new(0),
+            idle_timeout: AtomicU64::new(0),
+            max_lifetime: AtomicU64::new(0),
+            test_on_check_out: AtomicBool::new(false),
+            update: Mutex::new(()),
+        };
+        settings.set(config);
+        settings
+    }
+
+    // Fields are loaded one at a time, so this may mix the values from before
+    // and after a concurrent update.
+    pub fn get(&self) -> RuntimeConfig {
+        RuntimeConfig {
+            connection_timeout: from_nanos(self.connection_timeout.load(Ordering::Relaxed))
+                .unwrap(),
+            idle_timeout: from_nanos(self.idle_timeout.load(Ordering::Relaxed)),
+            max_lifetime: from_nanos(self.max_lifetime.load(Ordering::Relaxed)),
+            test_on_check_out: self.test_on_check_out.load(Ordering::Relaxed),
+            _p: (),
+        }
+    }
+
+    // `f` is run without blocking readers, but must not update the settings
+    // itself.
+    pub fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut RuntimeConfig),
+    {
+        let _guard = self.update.lock();
+        let mut config = self.get();
+        f(&mut config);
+        config.validate();
+        self.set(config);
+    }
+
+    fn set(&self, config: RuntimeConfig) {
+        self.connection_timeout
+            .store(to_nanos(Some(config.connection_timeout)), Ordering::Relaxed);
+        self.idle_timeout
+            .store(to_nanos(config.idle_timeout), Ordering::Relaxed);
+        self.max_lifetime
+            .store(to_nanos(config.max_lifetime), Ordering::Relaxed);
+        self.test_on_check_out
+            .store(config.test_on_check_out, Ordering::Relaxed);
+    }
+}
+
+// Durations too long to fit are clamped to about 584 years.
+fn to_nanos(duration: Option<Duration>) -> u64 {
+    duration.map_or(0, |d| u64::try_from(d.as_nanos()).unwrap_or(u64::MAX))
+}
+
+fn from_nanos(nanos: u64) -> Option<Duration> {
+    if nanos == 0 {
+        None
+    } else {
+        Some(Duration::from_nanos(nanos))
+    }
+}
@@ -54,11 +54,41 @@ impl AcquireEvent {
     }
 }
 
+/// The reason a connection was released.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReleaseReason {
+    /// `ManageConnection::has_broken` reported the connection as broken, or
+    /// it was found to be broken within `Pool::with`.
+    Broken,
+    /// The connection failed validation, or was rejected by the connection
+    /// customizer.
+    Invalid,
+    /// The connection sat idle for longer than the idle timeout.
+    IdleTimeout,
+    /// The connection exceeded its maximum lifetime.
+    MaxLifetime,
+    /// The connection was checked out the maximum number of times.
+    MaxUses,
+    /// The connection was closed to make room for another one.
+    Evicted,
+    /// The connection was rejected by a predicate passed to `Pool::retain`.
+    Rejected,
+    /// The connection was closed by `Pool::invalidate_all`.
+    Invalidated,
+    /// The connection was replaced by `Pool::recycle_all`.
+    Recycled,
+    /// The connection was established after the attempt had already been
+    /// given up on.
+    ConnectTimeout,
+}
+
 /// Information about a release event.
 #[derive(Debug)]
 pub struct ReleaseEvent {
     pub(crate) id: u64,
     pub(crate) age: Duration,
+    pub(crate) reason: ReleaseReason,
 }
 
 impl ReleaseEvent {
@@ -73,6 +103,12 @@ impl ReleaseEvent {
     pub fn age(&self) -> Duration {
         self.age
     }
+
+    /// Returns the reason the connection was released.
+    #[inline]
+    pub fn reason(&self) -> ReleaseReason {
+        self.reason
+    }
 }
 
 /// Information about a checkout event.
@@ -0,0 +1,302 @@
+//! Failover between a primary endpoint and its standbys.
+//!
+//! A `FailoverManager` wraps a list of `ManageConnection` implementations in
+//! priority order. Only one of them is active at a time: new connections are
+//! made to the active endpoint, and when that fails the others are tried in
+//! priority order, with the first to succeed becoming the new active
+//! endpoint.
+//!
+//! Connections to an endpoint which is no longer active are evicted from the
+//! pool. Idle ones are closed as soon as the active endpoint changes, and
+//! checked out ones when they are returned to the pool. They also fail
+//! `ManageConnection::is_valid`.
+//!
+//! ```rust,ignore
+//! use r2d2::failover::FailoverManager;
+//!
+//! let manager = FailoverManager::new(vec![
+//!     FooConnectionManager::new("primary:1234"),
+//!     FooConnectionManager::new("standby:1234"),
+//! ]);
+//! let pool = r2d2::Pool::new(manager).unwrap();
+//! ```
+use parking_lot::Mutex;
+use std::error;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::future::connect_first;
+use crate::{BoxFuture, Evictor, ManageConnection};
+
+struct Active<M>
+where
+    M: ManageConnection,
+{
+    endpoint: AtomicUsize,
+    evictor: Mutex<Option<Evictor<FailoverManager<M>>>>,
+}
+
+impl<M> Active<M>
+where
+    M: ManageConnection,
+{
+    fn switch(&self, current: usize, endpoint: usize) {
+        if endpoint == current {
+            return;
+        }
+
+        // If another thread has already failed over somewhere else, this
+        // connection will simply be evicted later.
+        if self
+            .endpoint
+            .compare_exchange(current, endpoint, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+
+        let evictor = self.evictor.lock().clone();
+        if let Some(evictor) = evictor {
+            evictor.retain(move |conn, _| conn.endpoint == endpoint);
+        }
+    }
+}
+
+/// A `ManageConnection` which fails over between several inner managers.
+pub struct FailoverManager<M>
+where
+    M: ManageConnection,
+{
+    managers: Vec<M>,
+    active: Arc<Active<M>>,
+}
+
+impl<M> fmt::Debug for FailoverManager<M>
+where
+    M: ManageConnection + fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("FailoverManager")
+            .field("managers", &self.managers)
+            .field("active", &self.active_endpoint())
+            .finish()
+    }
+}
+
+impl<M> FailoverManager<M>
+where
+    M: ManageConnection,
+{
+    /// Creates a new `FailoverManager` over the provided managers, in priority
+    /// order.
+    ///
+    /// The first manager is initially active.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `managers` is empty.
+    pub fn new(managers: Vec<M>) -> FailoverManager<M> {
+        assert!(!managers.is_empty(), "managers must not be empty");
+
+        FailoverManager {
+            managers,
+            active: Arc::new(Active {
+                endpoint: AtomicUsize::new(0),
+                evictor: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Returns the index of the currently active endpoint.
+    pub fn active_endpoint(&self) -> usize {
+        self.active.endpoint.load(Ordering::SeqCst)
+    }
+
+    /// Returns the manager for an endpoint.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `endpoint` is out of bounds.
+    pub fn manager(&self, endpoint: usize) -> &M {
+        &self.managers[endpoint]
+    }
+}
+
+impl<M> ManageConnection for FailoverManager<M>
+where
+    M: ManageConnection,
+{
+    type Connection = FailoverConnection<M::Connection>;
+    type Error = FailoverError<M::Error>;
+
+    fn connect(&self) -> Result<FailoverConnection<M::Connection>, FailoverError<M::Error>> {
+        self.connect_with(|manager| manager.connect())
+    }
+
+    fn connect_with_deadline(
+        &self,
+        deadline: Instant,
+    ) -> Result<FailoverConnection<M::Connection>, FailoverError<M::Error>> {
+        self.connect_with(|manager| manager.connect_with_deadline(deadline))
+    }
+
+    fn is_valid(
+        &self,
+        conn: &mut FailoverConnection<M::Connection>,
+    ) -> Result<(), FailoverError<M::Error>> {
+        if conn.endpoint != self.active_endpoint() {
+            return Err(FailoverError::Inactive);
+        }
+
+        self.managers[conn.endpoint]
+            .is_valid(&mut conn.conn)
+            .map_err(FailoverError::Inner)
+    }
+
+    fn is_valid_with_deadline(
+        &self,
+        conn: &mut FailoverConnection<M::Connection>,
+        deadline: Instant,
+    ) -> Result<(), FailoverError<M::Error>> {
+        if conn.endpoint != self.active_endpoint() {
+            return Err(FailoverError::Inactive);
+        }
+
+        self.managers[conn.endpoint]
+            .is_valid_with_deadline(&mut conn.conn, deadline)
+            .map_err(FailoverError::Inner)
+    }
+
+    fn has_broken(&self, conn: &mut FailoverConnection<M::Connection>) -> bool {
+        conn.endpoint != self.active_endpoint()
+            || self.managers[conn.endpoint].has_broken(&mut conn.conn)
+    }
+
+    fn connect_async(
+        &self,
+    ) -> Option<
+        BoxFuture<'static, Result<FailoverConnection<M::Connection>, FailoverError<M::Error>>>,
+    > {
+        let active = self.active_endpoint();
+        let mut attempts = vec![];
+        for endpoint in self.candidates(active) {
+            attempts.push((endpoint, self.managers[endpoint].connect_async()?));
+        }
+        let active_endpoint = self.active.clone();
+
+        Some(Box::pin(async move {
+            let (endpoint, conn) = connect_first(attempts, |_| {})
+                .await
+                .map_err(FailoverError::Inner)?;
+            active_endpoint.switch(active, endpoint);
+            Ok(FailoverConnection { conn, endpoint })
+        }))
+    }
+
+    fn attach(&self, evictor: Evictor<Self>) {
+        *self.active.evictor.lock() = Some(evictor);
+    }
+}
+
+impl<M> FailoverManager<M>
+where
+    M: ManageConnection,
+{
+    // Returns the endpoints to try, in order.
+    fn candidates(&self, active: usize) -> impl Iterator<Item = usize> {
+        Some(active)
+            .into_iter()
+            .chain((0..self.managers.len()).filter(move |&i| i != active))
+    }
+
+    fn connect_with<F>(
+        &self,
+        connect: F,
+    ) -> Result<FailoverConnection<M::Connection>, FailoverError<M::Error>>
+    where
+        F: Fn(&M) -> Result<M::Connection, M::Error>,
+    {
+        let active = self.active_endpoint();
+
+        let mut last_error = None;
+        for endpoint in self.candidates(active) {
+            match connect(&self.managers[endpoint]) {
+                Ok(conn) => {
+                    self.active.switch(active, endpoint);
+                    return Ok(FailoverConnection { conn, endpoint });
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(FailoverError::Inner(last_error.unwrap()))
+    }
+}
+
+/// A connection created by a `FailoverManager`.
+///
+/// It dereferences to the inner manager's connection type.
+#[derive(Debug)]
+pub struct FailoverConnection<C> {
+    conn: C,
+    endpoint: usize,
+}
+
+impl<C> Deref for FailoverConnection<C> {
+    type Target = C;
+
+    fn deref(&self) -> &C {
+        &self.conn
+    }
+}
+
+impl<C> DerefMut for FailoverConnection<C> {
+    fn deref_mut(&mut self) -> &mut C {
+        &mut self.conn
+    }
+}
+
+impl<C> FailoverConnection<C> {
+    /// Returns the index of the endpoint this connection was made to.
+    pub fn endpoint(this: &Self) -> usize {
+        this.endpoint
+    }
+}
+
+/// The error type returned by a `FailoverManager`.
+#[derive(Debug)]
+pub enum FailoverError<E> {
+    /// An error returned by one of the inner managers.
+    Inner(E),
+    /// The connection was made to an endpoint which is no longer active.
+    Inactive,
+}
+
+impl<E> fmt::Display for FailoverError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FailoverError::Inner(ref e) => fmt::Display::fmt(e, fmt),
+            FailoverError::Inactive => {
+                fmt.write_str("connection was made to an endpoint which is no longer active")
+            }
+        }
+    }
+}
+
+impl<E> error::Error for FailoverError<E>
+where
+    E: error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            FailoverError::Inner(ref e) => Some(e),
+            FailoverError::Inactive => None,
+        }
+    }
+}
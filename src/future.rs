@@ -0,0 +1,323 @@
+use parking_lot::Mutex;
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+use crate::{
+    background_connect_done, init_connection, schedule_connect_timeout, ManageConnection,
+    SharedPool,
+};
+
+/// An owned, type-erased future.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A trait which provides connection-specific functionality for drivers with
+/// asynchronous APIs.
+///
+/// Wrap an implementation in an `AsyncManager` to use it with a `Pool`.
+pub trait AsyncManageConnection: Send + Sync + 'static {
+    /// The connection type this manager deals with.
+    type Connection: Send + 'static;
+
+    /// The error type returned by `Connection`s.
+    type Error: error::Error + 'static;
+
+    /// Returns a future which creates a new connection.
+    ///
+    /// The future may be polled from any of the pool's threads, so it must not
+    /// rely on being run within a particular executor. It also must not borrow
+    /// from the manager, so any state it needs should be cloned into it.
+    fn connect(&self) -> BoxFuture<'static, Result<Self::Connection, Self::Error>>;
+
+    /// Returns a future which determines if the connection is still connected
+    /// to the database.
+    ///
+    /// This is driven on the thread checking the connection, which is blocked
+    /// until it resolves.
+    fn is_valid<'a>(
+        &'a self,
+        conn: &'a mut Self::Connection,
+    ) -> BoxFuture<'a, Result<(), Self::Error>>;
+
+    /// *Quickly* determines if the connection is no longer usable.
+    ///
+    /// See `ManageConnection::has_broken`.
+    fn has_broken(&self, conn: &mut Self::Connection) -> bool;
+}
+
+/// An adapter allowing an `AsyncManageConnection` to be used as a
+/// `ManageConnection`.
+///
+/// Connections established by the pool in the background are made without
+/// blocking any of its threads while they are being set up. Where the pool
+/// does block on a future, it gives up once the deadline passed to
+/// `connect_with_deadline` or `is_valid_with_deadline` does.
+#[derive(Debug)]
+pub struct AsyncManager<A>(A);
+
+impl<A> AsyncManager<A>
+where
+    A: AsyncManageConnection,
+{
+    /// Creates a new `AsyncManager`.
+    pub fn new(manager: A) -> AsyncManager<A> {
+        AsyncManager(manager)
+    }
+
+    /// Returns a reference to the wrapped manager.
+    pub fn get_ref(&self) -> &A {
+        &self.0
+    }
+}
+
+impl<A> ManageConnection for AsyncManager<A>
+where
+    A: AsyncManageConnection,
+{
+    type Connection = A::Connection;
+    type Error = AsyncError<A::Error>;
+
+    fn connect(&self) -> Result<A::Connection, AsyncError<A::Error>> {
+        block_on(self.0.connect()).map_err(AsyncError::Inner)
+    }
+
+    fn connect_with_deadline(
+        &self,
+        deadline: Instant,
+    ) -> Result<A::Connection, AsyncError<A::Error>> {
+        match block_on_until(self.0.connect(), deadline) {
+            Some(result) => result.map_err(AsyncError::Inner),
+            None => Err(AsyncError::TimedOut),
+        }
+    }
+
+    fn is_valid(&self, conn: &mut A::Connection) -> Result<(), AsyncError<A::Error>> {
+        block_on(self.0.is_valid(conn)).map_err(AsyncError::Inner)
+    }
+
+    fn is_valid_with_deadline(
+        &self,
+        conn: &mut A::Connection,
+        deadline: Instant,
+    ) -> Result<(), AsyncError<A::Error>> {
+        match block_on_until(self.0.is_valid(conn), deadline) {
+            Some(result) => result.map_err(AsyncError::Inner),
+            None => Err(AsyncError::TimedOut),
+        }
+    }
+
+    fn has_broken(&self, conn: &mut A::Connection) -> bool {
+        self.0.has_broken(conn)
+    }
+
+    fn connect_async(&self) -> Option<ConnectFuture<Self>> {
+        let future = self.0.connect();
+        Some(Box::pin(
+            async move { future.await.map_err(AsyncError::Inner) },
+        ))
+    }
+}
+
+/// The error type returned by an `AsyncManager`.
+#[derive(Debug)]
+pub enum AsyncError<E> {
+    /// An error returned by the inner manager.
+    Inner(E),
+    /// The future did not complete before its deadline.
+    TimedOut,
+}
+
+impl<E> fmt::Display for AsyncError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsyncError::Inner(ref e) => fmt::Display::fmt(e, fmt),
+            AsyncError::TimedOut => fmt.write_str("timed out"),
+        }
+    }
+}
+
+impl<E> error::Error for AsyncError<E>
+where
+    E: error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            AsyncError::Inner(ref e) => Some(e),
+            AsyncError::TimedOut => None,
+        }
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// Drives a future to completion on the calling thread.
+fn block_on<F>(future: F) -> F::Output
+where
+    F: Future,
+{
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        match future.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+// Like `block_on`, but drops the future and returns `None` if it hasn't
+// completed by `deadline`.
+fn block_on_until<F>(future: F, deadline: Instant) -> Option<F::Output>
+where
+    F: Future,
+{
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return Some(output);
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        thread::park_timeout(deadline - now);
+    }
+}
+
+// Awaits each attempt's future in turn until one of them connects, returning
+// the connection along with that attempt's tag. `on_error` is called with the
+// tag of each attempt which fails.
+//
+// This lets managers wrapping several endpoints fall back between them in
+// `connect_async`. The futures can't borrow from the manager, so they are all
+// created up front, but each is only polled if the ones before it fail.
+// Errors aren't necessarily `Send`, so only the last one is kept.
+//
+// `attempts` must not be empty.
+pub(crate) async fn connect_first<T, C, E, F>(
+    attempts: Vec<(T, BoxFuture<'static, Result<C, E>>)>,
+    mut on_error: F,
+) -> Result<(T, C), E>
+where
+    F: FnMut(&T),
+{
+    let last = attempts.len() - 1;
+    for (i, (tag, future)) in attempts.into_iter().enumerate() {
+        match future.await {
+            Ok(conn) => return Ok((tag, conn)),
+            Err(e) => {
+                on_error(&tag);
+                if i == last {
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    unreachable!()
+}
+
+type ConnectFuture<M> =
+    BoxFuture<'static, Result<<M as ManageConnection>::Connection, <M as ManageConnection>::Error>>;
+
+// A background connection attempt. Each time it is woken, it is polled again
+// on the pool's thread pool.
+struct ConnectTask<M>
+where
+    M: ManageConnection,
+{
+    shared: Weak<SharedPool<M>>,
+    future: Mutex<Option<ConnectFuture<M>>>,
+    delay: Duration,
+    generation: u64,
+}
+
+impl<M> Wake for ConnectTask<M>
+where
+    M: ManageConnection,
+{
+    fn wake(self: Arc<Self>) {
+        let shared = match self.shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
+
+        if let Some(ref thread_pool) = shared.config.thread_pool {
+            thread_pool.execute(move || poll_connect(self));
+        }
+    }
+}
+
+// Starts driving a connection future on the thread pool. The outcome is
+// handled as if `connect` had returned it after `delay` worth of retries.
+pub(crate) fn spawn_connect<M>(
+    shared: &Arc<SharedPool<M>>,
+    future: ConnectFuture<M>,
+    delay: Duration,
+) where
+    M: ManageConnection,
+{
+    let task = Arc::new(ConnectTask {
+        shared: Arc::downgrade(shared),
+        future: Mutex::new(Some(future)),
+        delay,
+        generation: shared.generations.current(),
+    });
+
+    // dropping the future cancels the attempt
+    let abandon = {
+        let task = task.clone();
+        move || task.future.lock().take().is_some()
+    };
+    schedule_connect_timeout(shared, delay, abandon);
+
+    poll_connect(task);
+}
+
+fn poll_connect<M>(task: Arc<ConnectTask<M>>)
+where
+    M: ManageConnection,
+{
+    let shared = match task.shared.upgrade() {
+        Some(shared) => shared,
+        None => return,
+    };
+
+    let mut slot = task.future.lock();
+    // the future may have completed before a spurious wakeup
+    let future = match *slot {
+        Some(ref mut future) => future,
+        None => return,
+    };
+
+    let waker = Waker::from(task.clone());
+    let result = match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+        Poll::Ready(result) => result,
+        Poll::Pending => return,
+    };
+    *slot = None;
+    drop(slot);
+
+    let result = result.and_then(|conn| init_connection(&shared, conn, task.generation));
+    background_connect_done(&shared, result, task.delay);
+}
@@ -0,0 +1,71 @@
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tracks the generations of a pool's connections.
+///
+/// A connection's generation is the pool's generation when the attempt to
+/// open it started. `Pool::invalidate_all` and `Pool::recycle_all` start a
+/// new generation to tell connections opened afterwards apart from older
+/// ones.
+pub struct Generations {
+    current: AtomicU64,
+    // connections from generations before this one are closed on sight
+    invalid_below: AtomicU64,
+    // the number of live connections from each generation
+    live: Mutex<BTreeMap<u64, u32>>,
+}
+
+impl Generations {
+    pub fn new() -> Generations {
+        Generations {
+            current: AtomicU64::new(0),
+            invalid_below: AtomicU64::new(0),
+            live: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the current generation.
+    pub fn current(&self) -> u64 {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    /// Starts a new generation, returning it.
+    pub fn advance(&self) -> u64 {
+        self.current.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Starts a new generation, marking connections from all previous ones as
+    /// invalid. Returns the new generation.
+    pub fn invalidate(&self) -> u64 {
+        let generation = self.advance();
+        self.invalid_below.fetch_max(generation, Ordering::SeqCst);
+        generation
+    }
+
+    /// Returns if connections from the generation have been invalidated.
+    pub fn is_invalid(&self, generation: u64) -> bool {
+        generation < self.invalid_below.load(Ordering::SeqCst)
+    }
+
+    /// Returns the oldest generation with a live connection.
+    pub fn oldest(&self) -> Option<u64> {
+        self.live.lock().keys().next().cloned()
+    }
+
+    /// Records a new connection from the generation.
+    pub fn register(&self, generation: u64) {
+        *self.live.lock().entry(generation).or_insert(0) += 1;
+    }
+
+    /// Forgets about a connection from the generation which has been closed.
+    pub fn release(&self, generation: u64) {
+        let mut live = self.live.lock();
+        if let Some(count) = live.get_mut(&generation) {
+            *count -= 1;
+            if *count == 0 {
+                live.remove(&generation);
+            }
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::thread::{self, ThreadId};
+
+/// Tracks the number of connections each thread has checked out, for
+/// `Builder::detect_reentrant_checkout`.
+///
+/// Connections are counted against the thread which checked them out, even if
+/// they are later moved to and returned by another thread.
+pub struct Holders {
+    counts: Mutex<HashMap<ThreadId, u32>>,
+}
+
+impl Holders {
+    pub fn new() -> Holders {
+        Holders {
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the number of connections held by the current thread.
+    pub fn held(&self) -> u32 {
+        let id = thread::current().id();
+        self.counts.lock().get(&id).cloned().unwrap_or(0)
+    }
+
+    /// Records a connection checked out by the current thread, returning the
+    /// thread's ID to release it with.
+    pub fn acquire(&self) -> ThreadId {
+        let id = thread::current().id();
+        *self.counts.lock().entry(id).or_insert(0) += 1;
+        id
+    }
+
+    /// Forgets about a connection checked out by the thread.
+    pub fn release(&self, id: ThreadId) {
+        let mut counts = self.counts.lock();
+        if let Some(count) = counts.get_mut(&id) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&id);
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::IdleConn;
+
+static NEXT_THREAD_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static THREAD_INDEX: usize = NEXT_THREAD_INDEX.fetch_add(1, Ordering::Relaxed);
+}
+
+// Aligned to keep shards which are hammered by different threads off of each
+// other's cache lines.
+#[repr(align(64))]
+struct Shard<C>(Mutex<Vec<IdleConn<C>>>);
+
+/// The pool's idle connections, split across a number of independently locked
+/// shards.
+///
+/// Each thread has a home shard it returns connections to and takes them from
+/// first, stealing from the other shards when its own is empty.
+pub struct IdleConns<C> {
+    shards: Box<[Shard<C>]>,
+    len: AtomicUsize,
+}
+
+impl<C> IdleConns<C> {
+    pub fn new(shards: u32, capacity: u32) -> IdleConns<C> {
+        let capacity = capacity / shards + 1;
+        let shards = (0..shards)
+            .map(|_| Shard(Mutex::new(Vec::with_capacity(capacity as usize))))
+            .collect::<Vec<_>>();
+
+        IdleConns {
+            shards: shards.into_boxed_slice(),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the number of idle connections.
+    ///
+    /// This is sequentially consistent with `push` so that a thread which
+    /// registers itself as waiting and then observes an empty list can rely
+    /// on the pusher observing its registration.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn home(&self) -> usize {
+        if self.shards.len() == 1 {
+            return 0;
+        }
+        THREAD_INDEX.with(|i| *i) % self.shards.len()
+    }
+
+    pub fn push(&self, conn: IdleConn<C>) {
+        let mut shard = self.shards[self.home()].0.lock();
+        shard.push(conn);
+        self.len.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn pop(&self) -> Option<IdleConn<C>> {
+        if self.is_empty() {
+            return None;
+        }
+
+        // start at the home shard and wrap around
+        let (others, rest) = self.shards.split_at(self.home());
+        for shard in rest.iter().chain(others) {
+            let mut shard = shard.0.lock();
+            if let Some(conn) = shard.pop() {
+                self.len.fetch_sub(1, Ordering::SeqCst);
+                return Some(conn);
+            }
+        }
+
+        None
+    }
+
+    /// Removes and returns the first idle connection matching the predicate.
+    pub fn take<F>(&self, mut f: F) -> Option<IdleConn<C>>
+    where
+        F: FnMut(&IdleConn<C>) -> bool,
+    {
+        for shard in self.shards.iter() {
+            let mut shard = shard.0.lock();
+            if let Some(idx) = shard.iter().position(&mut f) {
+                self.len.fetch_sub(1, Ordering::SeqCst);
+                return Some(shard.remove(idx));
+            }
+        }
+
+        None
+    }
+
+    /// Calls the closure on each idle connection.
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&IdleConn<C>),
+    {
+        for shard in self.shards.iter() {
+            shard.0.lock().iter().for_each(&mut f);
+        }
+    }
+
+    /// Removes and returns all idle connections matching the predicate.
+    ///
+    /// Shards are processed one at a time, so this is not atomic with respect
+    /// to concurrent pushes and pops.
+    pub fn drain_where<F>(&self, mut f: F) -> Vec<IdleConn<C>>
+    where
+        F: FnMut(&IdleConn<C>) -> bool,
+    {
+        let mut drained = vec![];
+
+        for shard in self.shards.iter() {
+            let mut shard = shard.0.lock();
+            let mut i = 0;
+            while i < shard.len() {
+                if f(&shard[i]) {
+                    drained.push(shard.remove(i));
+                    self.len.fetch_sub(1, Ordering::SeqCst);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        drained
+    }
+}
@@ -0,0 +1,518 @@
+//! Pools of connections partitioned by key.
+//!
+//! A `KeyedPool` manages a separate pool of connections for each key, for
+//! example one per tenant where each tenant has its own database credentials.
+//! The per-key pools share a single thread pool and reaper, and the total
+//! number of connections across all keys is capped.
+//!
+//! ```rust,ignore
+//! use r2d2::keyed::KeyedPool;
+//!
+//! let pool = KeyedPool::builder()
+//!     .max_connections(500)
+//!     .key_config(|_tenant: &TenantId, builder| builder.max_size(5))
+//!     .build(|tenant: &TenantId| FooConnectionManager::new(&tenant.url()));
+//!
+//! let conn = pool.get(&tenant).unwrap();
+//! ```
+use parking_lot::Mutex;
+use scheduled_thread_pool::{JobHandle, ScheduledThreadPool};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+
+use crate::event::ReleaseReason;
+use crate::{
+    add_connection, drop_conns, reap, Builder, Error, ManageConnection, Pool, PooledConnection,
+    State,
+};
+
+/// A cap on the number of connections shared between several pools.
+///
+/// A pool holds one unit of the limit for each of its open and pending
+/// connections.
+pub(crate) struct ConnectionLimit {
+    max: u32,
+    count: AtomicU32,
+    // set when a pool has been refused a connection, so that the next release
+    // or idle connection gives it another chance
+    exhausted: AtomicBool,
+    // called whenever a pool is refused a connection, and on the next release
+    // or idle connection
+    on_exhausted: Box<dyn Fn() + Send + Sync>,
+}
+
+impl ConnectionLimit {
+    pub(crate) fn acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::SeqCst);
+        loop {
+            if count >= self.max {
+                self.exhausted.store(true, Ordering::SeqCst);
+                (self.on_exhausted)();
+                return false;
+            }
+
+            match self.count.compare_exchange_weak(
+                count,
+                count + 1,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => count = actual,
+            }
+        }
+    }
+
+    fn is_available(&self) -> bool {
+        self.count.load(Ordering::SeqCst) < self.max
+    }
+
+    pub(crate) fn release(&self, n: u32) {
+        if n == 0 {
+            return;
+        }
+
+        self.count.fetch_sub(n, Ordering::SeqCst);
+        self.retry();
+    }
+
+    // Called when a pool has a connection go idle, which a pool refused by the
+    // limit may be able to reclaim.
+    pub(crate) fn idle(&self) {
+        self.retry();
+    }
+
+    fn retry(&self) {
+        if self.exhausted.swap(false, Ordering::SeqCst) {
+            (self.on_exhausted)();
+        }
+    }
+}
+
+type KeyConfig<K, M> = dyn Fn(&K, Builder<M>) -> Builder<M> + Send + Sync;
+
+struct Entry<M>
+where
+    M: ManageConnection,
+{
+    pool: Pool<M>,
+    last_used: Instant,
+}
+
+struct KeyedShared<K, M>
+where
+    M: ManageConnection,
+{
+    pools: Mutex<HashMap<K, Entry<M>>>,
+    manager_factory: Box<dyn Fn(&K) -> M + Send + Sync>,
+    key_config: Box<KeyConfig<K, M>>,
+    thread_pool: Arc<ScheduledThreadPool>,
+    limit: Arc<ConnectionLimit>,
+    idle_key_timeout: Option<Duration>,
+    reaper: Mutex<Option<JobHandle>>,
+}
+
+impl<K, M> Drop for KeyedShared<K, M>
+where
+    M: ManageConnection,
+{
+    fn drop(&mut self) {
+        // the thread pool may be shared with others and outlive us
+        if let Some(reaper) = self.reaper.lock().take() {
+            reaper.cancel();
+        }
+    }
+}
+
+/// A builder for a `KeyedPool`.
+pub struct KeyedBuilder<K, M>
+where
+    M: ManageConnection,
+{
+    max_connections: u32,
+    idle_key_timeout: Option<Duration>,
+    thread_pool: Option<Arc<ScheduledThreadPool>>,
+    key_config: Box<KeyConfig<K, M>>,
+    reaper_rate: Duration,
+}
+
+impl<K, M> fmt::Debug for KeyedBuilder<K, M>
+where
+    M: ManageConnection,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("KeyedBuilder")
+            .field("max_connections", &self.max_connections)
+            .field("idle_key_timeout", &self.idle_key_timeout)
+            .finish()
+    }
+}
+
+impl<K, M> Default for KeyedBuilder<K, M>
+where
+    M: ManageConnection,
+{
+    fn default() -> KeyedBuilder<K, M> {
+        KeyedBuilder {
+            max_connections: 100,
+            idle_key_timeout: Some(Duration::from_secs(10 * 60)),
+            thread_pool: None,
+            key_config: Box::new(|_, builder| builder),
+            reaper_rate: Duration::from_secs(30),
+        }
+    }
+}
+
+impl<K, M> KeyedBuilder<K, M>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    M: ManageConnection,
+{
+    /// Constructs a new `KeyedBuilder`.
+    ///
+    /// Parameters are initialized with their default values.
+    pub fn new() -> KeyedBuilder<K, M> {
+        KeyedBuilder::default()
+    }
+
+    /// Sets the maximum number of connections managed by the pool across all
+    /// keys.
+    ///
+    /// When a key needs a new connection and this limit has been reached, an
+    /// idle connection belonging to another key is closed to make room for
+    /// it, if there is one.
+    ///
+    /// Defaults to 100.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_connections` is 0.
+    pub fn max_connections(mut self, max_connections: u32) -> KeyedBuilder<K, M> {
+        assert!(max_connections > 0, "max_connections must be positive");
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets how long a key may go unused before its pool is removed.
+    ///
+    /// A key is only removed once none of its connections are checked out,
+    /// and its remaining idle connections are closed along with it.
+    ///
+    /// Defaults to 10 minutes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idle_key_timeout` is the zero `Duration`.
+    pub fn idle_key_timeout(mut self, idle_key_timeout: Option<Duration>) -> KeyedBuilder<K, M> {
+        assert_ne!(
+            idle_key_timeout,
+            Some(Duration::from_secs(0)),
+            "idle_key_timeout must be positive"
+        );
+        self.idle_key_timeout = idle_key_timeout;
+        self
+    }
+
+    /// Sets the thread pool shared by all keys for asynchronous operations
+    /// such as connection creation and reaping.
+    ///
+    /// Defaults to a new pool with 3 threads.
+    pub fn thread_pool(mut self, thread_pool: Arc<ScheduledThreadPool>) -> KeyedBuilder<K, M> {
+        self.thread_pool = Some(thread_pool);
+        self
+    }
+
+    /// Sets a function used to configure the pool for each key.
+    ///
+    /// It is passed a `Builder` with `min_idle` set to 0, and may set any
+    /// option other than `thread_pool`, `synchronous` and
+    /// `automatic_reaping`, which are controlled by the `KeyedPool`.
+    ///
+    /// Defaults to using the `Builder` as is.
+    pub fn key_config<F>(mut self, key_config: F) -> KeyedBuilder<K, M>
+    where
+        F: Fn(&K, Builder<M>) -> Builder<M> + Send + Sync + 'static,
+    {
+        self.key_config = Box::new(key_config);
+        self
+    }
+
+    // used by tests
+    #[allow(dead_code)]
+    pub(crate) fn reaper_rate(mut self, reaper_rate: Duration) -> KeyedBuilder<K, M> {
+        self.reaper_rate = reaper_rate;
+        self
+    }
+
+    /// Consumes the builder, returning a new pool which creates the manager
+    /// for each key with `manager_factory`.
+    ///
+    /// Pools are created for keys as they are first used, and do not wait for
+    /// any connections to be established.
+    pub fn build<F>(self, manager_factory: F) -> KeyedPool<K, M>
+    where
+        F: Fn(&K) -> M + Send + Sync + 'static,
+    {
+        #[allow(deprecated)]
+        let thread_pool = match self.thread_pool {
+            Some(thread_pool) => thread_pool,
+            None => Arc::new(ScheduledThreadPool::with_name("r2d2-worker-{}", 3)),
+        };
+
+        let KeyedBuilder {
+            max_connections,
+            idle_key_timeout,
+            key_config,
+            reaper_rate,
+            ..
+        } = self;
+
+        let shared = Arc::new_cyclic(|weak: &Weak<KeyedShared<K, M>>| {
+            let weak = weak.clone();
+            let scheduled = Arc::new(AtomicBool::new(false));
+            let rebalance_pool = thread_pool.clone();
+            let on_exhausted = move || {
+                // this is called with a pool locked, so the real work is
+                // left to the thread pool
+                if scheduled.swap(true, Ordering::SeqCst) {
+                    return;
+                }
+                let weak = weak.clone();
+                let scheduled = scheduled.clone();
+                rebalance_pool.execute(move || {
+                    scheduled.store(false, Ordering::SeqCst);
+                    if let Some(shared) = weak.upgrade() {
+                        rebalance(&shared);
+                    }
+                });
+            };
+
+            KeyedShared {
+                pools: Mutex::new(HashMap::new()),
+                manager_factory: Box::new(manager_factory),
+                key_config,
+                thread_pool: thread_pool.clone(),
+                limit: Arc::new(ConnectionLimit {
+                    max: max_connections,
+                    count: AtomicU32::new(0),
+                    exhausted: AtomicBool::new(false),
+                    on_exhausted: Box::new(on_exhausted),
+                }),
+                idle_key_timeout,
+                reaper: Mutex::new(None),
+            }
+        });
+
+        let s = Arc::downgrade(&shared);
+        let reaper =
+            thread_pool.execute_at_fixed_rate(reaper_rate, reaper_rate, move || reap_keys(&s));
+        *shared.reaper.lock() = Some(reaper);
+
+        KeyedPool(shared)
+    }
+}
+
+/// A connection pool which manages a separate set of connections for each
+/// key.
+pub struct KeyedPool<K, M>(Arc<KeyedShared<K, M>>)
+where
+    M: ManageConnection;
+
+/// Returns a new `KeyedPool` referencing the same state as `self`.
+impl<K, M> Clone for KeyedPool<K, M>
+where
+    M: ManageConnection,
+{
+    fn clone(&self) -> KeyedPool<K, M> {
+        KeyedPool(self.0.clone())
+    }
+}
+
+impl<K, M> fmt::Debug for KeyedPool<K, M>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    M: ManageConnection,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("KeyedPool")
+            .field("keys", &self.0.pools.lock().len())
+            .field("state", &self.state())
+            .field("max_connections", &self.0.limit.max)
+            .finish()
+    }
+}
+
+impl<K, M> KeyedPool<K, M>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    M: ManageConnection,
+{
+    /// Returns a builder type to configure a new keyed pool.
+    pub fn builder() -> KeyedBuilder<K, M> {
+        KeyedBuilder::new()
+    }
+
+    /// Retrieves a connection for a key.
+    ///
+    /// Waits for at most the connection timeout configured for the key before
+    /// returning an error.
+    pub fn get(&self, key: &K) -> Result<PooledConnection<M>, Error> {
+        self.pool(key).get()
+    }
+
+    /// Retrieves a connection for a key, waiting for at most `timeout`.
+    pub fn get_timeout(&self, key: &K, timeout: Duration) -> Result<PooledConnection<M>, Error> {
+        self.pool(key).get_timeout(timeout)
+    }
+
+    /// Returns the pool for a key, creating it if necessary.
+    ///
+    /// A key is never removed while a handle to its pool is held.
+    pub fn pool(&self, key: &K) -> Pool<M> {
+        let mut pools = self.0.pools.lock();
+
+        if let Some(entry) = pools.get_mut(key) {
+            entry.last_used = Instant::now();
+            return entry.pool.clone();
+        }
+
+        let builder = (self.0.key_config)(key, Pool::builder().min_idle(Some(0)))
+            .thread_pool(self.0.thread_pool.clone())
+            .synchronous(false)
+            .automatic_reaping(false)
+            .connection_limit(self.0.limit.clone());
+        let pool = builder.build_unchecked((self.0.manager_factory)(key));
+
+        let entry = Entry {
+            pool: pool.clone(),
+            last_used: Instant::now(),
+        };
+        pools.insert(key.clone(), entry);
+        pool
+    }
+
+    /// Returns the keys which currently have a pool.
+    pub fn keys(&self) -> Vec<K> {
+        self.0.pools.lock().keys().cloned().collect()
+    }
+
+    /// Returns information about the current state of the pool, summed over
+    /// all keys.
+    pub fn state(&self) -> State {
+        let pools = self.0.pools.lock();
+        let mut state = State {
+            connections: 0,
+            idle_connections: 0,
+            _p: (),
+        };
+        for entry in pools.values() {
+            state.connections += entry.pool.0.num_conns();
+            state.idle_connections += entry.pool.0.idle_conns();
+        }
+        state
+    }
+
+    /// Returns the configured maximum number of connections across all keys.
+    pub fn max_connections(&self) -> u32 {
+        self.0.limit.max
+    }
+}
+
+// Hands connections to keys which are waiting for one but were refused by the
+// limit, closing idle connections of other keys to make room.
+fn rebalance<K, M>(keyed: &KeyedShared<K, M>)
+where
+    M: ManageConnection,
+{
+    let pools = keyed
+        .pools
+        .lock()
+        .values()
+        .map(|entry| entry.pool.clone())
+        .collect::<Vec<_>>();
+
+    for pool in &pools {
+        let shared = &pool.0;
+
+        // a thread which was refused a connection registers as a waiter
+        // before unlocking the pool, so it can't be missed here
+        let mut internals = shared.internals.lock();
+        if shared.waiters.load(Ordering::SeqCst) == 0
+            || !shared.idle.is_empty()
+            || shared.pending_conns() > 0
+            || shared.at_capacity()
+        {
+            continue;
+        }
+
+        // checked first so that we don't reschedule ourselves by being refused
+        if keyed.limit.is_available() {
+            add_connection(shared, &mut internals);
+            if shared.pending_conns() > 0 {
+                continue;
+            }
+        }
+        drop(internals);
+
+        // prefer keys holding more idle connections than they need
+        let victim = pools
+            .iter()
+            .filter(|p| !Arc::ptr_eq(&p.0, shared))
+            .filter(|p| p.0.idle_conns() > p.0.min_idle())
+            .max_by_key(|p| p.0.idle_conns().saturating_sub(p.0.min_idle()));
+        let victim = match victim {
+            Some(victim) => victim,
+            None => return,
+        };
+        // the victim's idle connections may be sitting in threads' caches
+        victim.0.spill_cached(&mut victim.0.internals.lock());
+        if let Some(conn) = victim.0.idle.pop() {
+            let conns = vec![(conn.conn, ReleaseReason::Evicted)];
+            drop_conns(&victim.0, victim.0.internals.lock(), conns);
+            add_connection(shared, &mut shared.internals.lock());
+        }
+    }
+}
+
+fn reap_keys<K, M>(shared: &Weak<KeyedShared<K, M>>)
+where
+    K: Hash + Eq,
+    M: ManageConnection,
+{
+    let shared = match shared.upgrade() {
+        Some(shared) => shared,
+        None => return,
+    };
+
+    let pools = shared
+        .pools
+        .lock()
+        .values()
+        .map(|entry| entry.pool.clone())
+        .collect::<Vec<_>>();
+    for pool in &pools {
+        reap(&pool.0, pool.0.internals.lock());
+    }
+    drop(pools);
+
+    let timeout = match shared.idle_key_timeout {
+        Some(timeout) => timeout,
+        None => return,
+    };
+
+    let mut removed = vec![];
+    shared.pools.lock().retain(|_, entry| {
+        // if only the map holds a handle to the pool, nothing is checked out
+        let expired = entry.last_used.elapsed() >= timeout && Arc::strong_count(&entry.pool.0) == 1;
+        if expired {
+            removed.push(entry.pool.clone());
+        }
+        !expired
+    });
+    // close the removed pools' connections without the map locked
+    drop(removed);
+}
@@ -42,24 +42,47 @@
 use log::error;
 
 use parking_lot::{Condvar, Mutex, MutexGuard};
+use scheduled_thread_pool::{JobHandle, ScheduledThreadPool};
 use std::cmp;
 use std::error;
 use std::fmt;
-use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Weak};
+use std::thread;
 use std::time::{Duration, Instant};
 
-pub use crate::config::Builder;
+use crate::cache::ThreadCache;
 use crate::config::Config;
-use crate::event::{AcquireEvent, CheckinEvent, CheckoutEvent, ReleaseEvent, TimeoutEvent};
+pub use crate::config::{Builder, RuntimeConfig};
+use crate::event::{
+    AcquireEvent, CheckinEvent, CheckoutEvent, ReleaseEvent, ReleaseReason, TimeoutEvent,
+};
 pub use crate::event::{HandleEvent, NopEventHandler};
 pub use crate::extensions::Extensions;
-
+pub use crate::future::{AsyncError, AsyncManageConnection, AsyncManager, BoxFuture};
+use crate::generation::Generations;
+use crate::holders::Holders;
+use crate::idle::IdleConns;
+use crate::retain::{RetainFn, Retains};
+#[cfg(feature = "serde")]
+pub use crate::settings::{EnvError, PoolConfig};
+
+pub mod balance;
+mod cache;
 mod config;
 pub mod event;
 mod extensions;
+pub mod failover;
+mod future;
+mod generation;
+mod holders;
+mod idle;
+pub mod keyed;
+mod retain;
+#[cfg(feature = "serde")]
+mod settings;
 
 #[cfg(test)]
 mod test;
@@ -77,12 +100,47 @@ pub trait ManageConnection: Send + Sync + 'static {
     /// Attempts to create a new connection.
     fn connect(&self) -> Result<Self::Connection, Self::Error>;
 
+    /// Attempts to create a new connection, giving up at `deadline`.
+    ///
+    /// The pool calls this rather than `connect`. The deadline is derived from
+    /// the pool's connect timeout, or its connection timeout if none is set.
+    /// In synchronous mode, where connections are established by the threads
+    /// waiting on them, it is also capped by that thread's timeout.
+    /// Implementations should use it to bound operations which can otherwise
+    /// hang, such as the TCP connect and TLS handshake.
+    ///
+    /// The default implementation ignores the deadline and calls `connect`.
+    #[allow(unused_variables)]
+    fn connect_with_deadline(&self, deadline: Instant) -> Result<Self::Connection, Self::Error> {
+        self.connect()
+    }
+
     /// Determines if the connection is still connected to the database.
     ///
     /// A standard implementation would check if a simple query like `SELECT 1`
     /// succeeds.
     fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error>;
 
+    /// Determines if the connection is still connected to the database,
+    /// giving up at `deadline`.
+    ///
+    /// The pool calls this rather than `is_valid`. The deadline is derived
+    /// from the pool's validation timeout, or its connection timeout if none
+    /// is set, and when a thread is waiting on the connection, that thread's
+    /// timeout. A check which completes at or after the deadline is treated
+    /// as a failure even if it succeeded, so implementations may return early
+    /// once it has passed.
+    ///
+    /// The default implementation ignores the deadline and calls `is_valid`.
+    #[allow(unused_variables)]
+    fn is_valid_with_deadline(
+        &self,
+        conn: &mut Self::Connection,
+        deadline: Instant,
+    ) -> Result<(), Self::Error> {
+        self.is_valid(conn)
+    }
+
     /// *Quickly* determines if the connection is no longer usable.
     ///
     /// This will be called synchronously every time a connection is returned
@@ -93,6 +151,36 @@ pub trait ManageConnection: Send + Sync + 'static {
     /// has disconnected. Implementations that do not support this kind of
     /// fast health check may simply return `false`.
     fn has_broken(&self, conn: &mut Self::Connection) -> bool;
+
+    /// Returns a future which creates a new connection, if the manager can
+    /// do so asynchronously.
+    ///
+    /// When this returns a future, connections established in the background
+    /// are made by driving it from the pool's thread pool rather than by
+    /// calling `connect`, so that no thread is blocked while the connection
+    /// is being set up. `connect` is still used where the pool has to wait
+    /// for the connection anyway, such as in synchronous mode.
+    ///
+    /// This is implemented by `AsyncManager`, and the default implementation
+    /// returns `None`.
+    fn connect_async(&self) -> Option<BoxFuture<'static, Result<Self::Connection, Self::Error>>> {
+        None
+    }
+
+    /// Called when a pool is created with the manager, with a handle which
+    /// can be used to close that pool's connections.
+    ///
+    /// This lets managers which learn that many connections have become
+    /// unusable at once, such as `FailoverManager`, evict them right away
+    /// rather than waiting for them to fail a check.
+    ///
+    /// The default implementation does nothing.
+    fn attach(&self, evictor: Evictor<Self>)
+    where
+        Self: Sized,
+    {
+        let _ = evictor;
+    }
 }
 
 /// A trait which handles errors reported by the `ManageConnection`.
@@ -137,6 +225,40 @@ pub trait CustomizeConnection<C, E>: fmt::Debug + Send + Sync + 'static {
         Ok(())
     }
 
+    /// Called with connections when they are returned to the pool, before
+    /// they are made available to other users.
+    ///
+    /// This can be used to reset session state, for example by rolling back
+    /// an open transaction. It is not called for connections which
+    /// `ManageConnection::has_broken` reports as broken.
+    ///
+    /// The default implementation simply returns `Ok(())`.
+    ///
+    /// # Errors
+    ///
+    /// If this method returns an error, the connection will be discarded.
+    #[allow(unused_variables)]
+    fn on_checkin(&self, conn: &mut C) -> Result<(), E> {
+        Ok(())
+    }
+
+    /// Called with connections each time they are checked out of the pool,
+    /// after they have been validated.
+    ///
+    /// This can be used to apply per-checkout settings, such as a statement
+    /// timeout.
+    ///
+    /// The default implementation simply returns `Ok(())`.
+    ///
+    /// # Errors
+    ///
+    /// If this method returns an error, the connection will be discarded and
+    /// another one will be tried.
+    #[allow(unused_variables)]
+    fn on_checkout(&self, conn: &mut C) -> Result<(), E> {
+        Ok(())
+    }
+
     /// Called with connections when they are removed from the pool.
     ///
     /// The connections may be broken (as reported by `is_valid` or
@@ -158,6 +280,9 @@ struct Conn<C> {
     extensions: Extensions,
     birth: Instant,
     id: u64,
+    use_count: u64,
+    retain_epoch: u64,
+    generation: u64,
 }
 
 struct IdleConn<C> {
@@ -165,11 +290,31 @@ struct IdleConn<C> {
     idle_start: Instant,
 }
 
-struct PoolInternals<C> {
-    conns: Vec<IdleConn<C>>,
-    num_conns: u32,
-    pending_conns: u32,
+struct PoolInternals {
     last_error: Option<String>,
+    last_reap: Instant,
+    // unlike `last_error`, this isn't cleared once reported
+    last_failure: Option<(String, Instant)>,
+    connect_failures: u32,
+    last_connect: Option<Instant>,
+}
+
+impl PoolInternals {
+    fn record_error(&mut self, msg: String) {
+        self.last_failure = Some((msg.clone(), Instant::now()));
+        self.last_error = Some(msg);
+    }
+
+    fn connect_succeeded(&mut self) {
+        self.last_error = None;
+        self.connect_failures = 0;
+        self.last_connect = Some(Instant::now());
+    }
+
+    fn connect_failed(&mut self, msg: String) {
+        self.record_error(msg);
+        self.connect_failures += 1;
+    }
 }
 
 struct SharedPool<M>
@@ -178,312 +323,1354 @@ where
 {
     config: Config<M::Connection, M::Error>,
     manager: M,
-    internals: Mutex<PoolInternals<M::Connection>>,
+    internals: Mutex<PoolInternals>,
     cond: Condvar,
+    idle: IdleConns<M::Connection>,
+    // The counters are only modified with `internals` locked, but may be read
+    // without it as a hint to avoid taking the lock on the fast paths.
+    num_conns: AtomicU32,
+    pending_conns: AtomicU32,
+    waiters: AtomicU32,
+    // the subset of `waiters` in `get_many`
+    batch_waiters: AtomicU32,
+    reaper: Mutex<Option<JobHandle>>,
+    retains: Retains<M::Connection>,
+    generations: Generations,
+    recycle: Mutex<Option<Recycle<M::Connection>>>,
+    // only tracked if `detect_reentrant_checkout` is set
+    holders: Option<Holders>,
+    // only used if `thread_cache` is set
+    cache: Option<ThreadCache<M::Connection>>,
 }
 
-fn drop_conns<M>(
-    shared: &Arc<SharedPool<M>>,
-    mut internals: MutexGuard<PoolInternals<M::Connection>>,
-    conns: Vec<Conn<M::Connection>>,
-) where
+// An in-progress `Pool::recycle_all`.
+struct Recycle<C> {
+    below: u64,
+    interval: Duration,
+    // the connection being replaced while its replacement is established in
+    // the background
+    replacing: Option<Conn<C>>,
+    // when a connection was last recycled on checkin in synchronous mode
+    last: Option<Instant>,
+}
+
+impl<M> SharedPool<M>
+where
     M: ManageConnection,
 {
-    internals.num_conns -= conns.len() as u32;
-    establish_idle_connections(shared, &mut internals);
-    drop(internals); // make sure we run connection destructors without this locked
+    fn num_conns(&self) -> u32 {
+        self.num_conns.load(Ordering::SeqCst)
+    }
 
-    for conn in conns {
-        let event = ReleaseEvent {
-            id: conn.id,
-            age: conn.birth.elapsed(),
+    fn pending_conns(&self) -> u32 {
+        self.pending_conns.load(Ordering::SeqCst)
+    }
+
+    // Includes connections held in threads' caches.
+    fn idle_conns(&self) -> u32 {
+        let cached = self.cache.as_ref().map_or(0, |cache| cache.len());
+        (self.idle.len() + cached) as u32
+    }
+
+    fn runtime(&self) -> RuntimeConfig {
+        self.config.runtime.get()
+    }
+
+    fn at_capacity(&self) -> bool {
+        self.num_conns() + self.pending_conns() >= self.config.max_size
+    }
+
+    fn min_idle(&self) -> u32 {
+        self.config.min_idle.unwrap_or(self.config.max_size)
+    }
+
+    // The deadline for validating a connection starting now.
+    fn validation_deadline(&self) -> Instant {
+        let timeout = self
+            .config
+            .validation_timeout
+            .unwrap_or_else(|| self.runtime().connection_timeout);
+        Instant::now() + timeout
+    }
+
+    // The deadline passed to the manager for a connection attempt starting now.
+    fn connect_deadline(&self) -> Instant {
+        let timeout = self
+            .config
+            .connect_timeout
+            .unwrap_or_else(|| self.runtime().connection_timeout);
+        Instant::now() + timeout
+    }
+
+    fn push_idle(&self, conn: Conn<M::Connection>) {
+        self.idle.push(IdleConn {
+            conn,
+            idle_start: Instant::now(),
+        });
+        self.idle_added();
+    }
+
+    // Lets pools sharing our connection limit reclaim a new idle connection.
+    fn idle_added(&self) {
+        if let Some(ref limit) = self.config.connection_limit {
+            limit.idle();
+        }
+    }
+
+    // Like `push_idle`, but keeps the connection in the current thread's cache
+    // instead if nobody is waiting for one.
+    fn check_in_idle(&self, conn: Conn<M::Connection>) {
+        let mut conn = IdleConn {
+            conn,
+            idle_start: Instant::now(),
+        };
+        if let Some(ref cache) = self.cache {
+            match cache.put(conn, || self.waiters.load(Ordering::SeqCst) == 0) {
+                Ok(()) => return self.idle_added(),
+                Err(c) => conn = c,
+            }
+        }
+        self.idle.push(conn);
+        self.notify_waiter();
+        self.idle_added();
+    }
+
+    // Moves every cached connection to the shared idle list so waiting threads
+    // can get at them.
+    //
+    // `internals` is unused, but ensures the caller holds the lock. Since
+    // waiters register before calling this, nothing can be cached behind
+    // their backs afterwards.
+    fn spill_cached(&self, _: &mut PoolInternals) {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return,
+        };
+
+        let conns = cache.drain();
+        // the caller takes the first one itself
+        for _ in 1..conns.len() {
+            self.notify_locked();
+        }
+        for conn in conns {
+            self.idle.push(conn);
+        }
+    }
+
+    // Removes the idle connections, cached or not, for which the predicate
+    // returns true.
+    fn drain_idle_where<F>(&self, mut f: F) -> Vec<IdleConn<M::Connection>>
+    where
+        F: FnMut(&IdleConn<M::Connection>) -> bool,
+    {
+        let mut conns = self.idle.drain_where(&mut f);
+        if let Some(ref cache) = self.cache {
+            conns.extend(cache.drain_where(f));
+        }
+        conns
+    }
+
+    // In synchronous mode, connections from before `Pool::recycle_all` are
+    // closed as they are returned to the pool, at up to its rate.
+    fn recycle_on_checkin(&self, conn: &Conn<M::Connection>) -> bool {
+        if self.config.thread_pool.is_some() {
+            return false;
+        }
+
+        let mut recycle = self.recycle.lock();
+        let below = match *recycle {
+            Some(ref recycle) => recycle.below,
+            None => return false,
         };
-        shared.config.event_handler.handle_release(event);
-        shared.config.connection_customizer.on_release(conn.conn);
+        if self
+            .generations
+            .oldest()
+            .map_or(true, |oldest| oldest >= below)
+        {
+            *recycle = None;
+            return false;
+        }
+
+        let recycle = recycle.as_mut().unwrap();
+        if conn.generation >= below
+            || recycle
+                .last
+                .map_or(false, |last| last.elapsed() < recycle.interval)
+        {
+            return false;
+        }
+        recycle.last = Some(Instant::now());
+        true
+    }
+
+    // Wakes a thread blocked waiting for a connection, if there are any.
+    //
+    // Waiters register themselves with the lock held before checking the idle
+    // list, so a connection pushed before this is called can't be missed.
+    fn notify_waiter(&self) {
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _internals = self.internals.lock();
+            self.notify_locked();
+        }
+    }
+
+    // Like `notify_waiter`, but with the lock already held.
+    //
+    // A thread in `get_many` may not be able to use a single connection, so
+    // if there are any, everyone is woken to make sure the notification
+    // isn't lost on one of them.
+    fn notify_locked(&self) {
+        if self.batch_waiters.load(Ordering::SeqCst) > 0 {
+            self.cond.notify_all();
+        } else {
+            self.cond.notify_one();
+        }
     }
 }
 
-fn establish_idle_connections<M>(
-    shared: &Arc<SharedPool<M>>,
-    internals: &mut PoolInternals<M::Connection>,
-) where
+impl<M> Drop for SharedPool<M>
+where
     M: ManageConnection,
 {
-    let min = shared.config.min_idle.unwrap_or(shared.config.max_size);
-    let idle = internals.conns.len() as u32;
-    for _ in idle..min {
-        add_connection(shared, internals);
+    fn drop(&mut self) {
+        if let Some(reaper) = self.reaper.lock().take() {
+            reaper.cancel();
+        }
+
+        // cached connections would otherwise live on in their threads
+        if let Some(ref cache) = self.cache {
+            cache.drain();
+        }
+
+        // hand back the share of the limit held by connections which are
+        // closed or abandoned along with the pool
+        if let Some(ref limit) = self.config.connection_limit {
+            limit.release(self.num_conns() + self.pending_conns());
+        }
     }
 }
 
-fn add_connection<M>(shared: &Arc<SharedPool<M>>, internals: &mut PoolInternals<M::Connection>)
+// Returns the number of replacement connections requested.
+fn drop_conns<M>(
+    shared: &Arc<SharedPool<M>>,
+    mut internals: MutexGuard<PoolInternals>,
+    conns: Vec<(Conn<M::Connection>, ReleaseReason)>,
+) -> u32
 where
     M: ManageConnection,
 {
-    if internals.num_conns + internals.pending_conns >= shared.config.max_size {
-        return;
+    shared
+        .num_conns
+        .fetch_sub(conns.len() as u32, Ordering::SeqCst);
+    if let Some(ref limit) = shared.config.connection_limit {
+        limit.release(conns.len() as u32);
     }
+    let requested = establish_idle_connections(shared, &mut internals);
+    drop(internals); // make sure we run connection destructors without this locked
 
-    internals.pending_conns += 1;
-    inner(Duration::from_secs(0), shared);
-
-    fn inner<M>(delay: Duration, shared: &Arc<SharedPool<M>>)
-    where
-        M: ManageConnection,
-    {
-        let new_shared = Arc::downgrade(shared);
-        shared.config.thread_pool.execute_after(delay, move || {
-            let shared = match new_shared.upgrade() {
-                Some(shared) => shared,
-                None => return,
-            };
-
-            let conn = shared.manager.connect().and_then(|mut conn| {
-                shared
-                    .config
-                    .connection_customizer
-                    .on_acquire(&mut conn)
-                    .map(|_| conn)
-            });
-            match conn {
-                Ok(conn) => {
-                    let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed) as u64;
+    for (conn, reason) in conns {
+        release_conn(shared, conn, reason);
+    }
 
-                    let event = AcquireEvent { id };
-                    shared.config.event_handler.handle_acquire(event);
+    requested
+}
 
-                    let mut internals = shared.internals.lock();
-                    internals.last_error = None;
-                    let now = Instant::now();
-                    let conn = IdleConn {
-                        conn: Conn {
-                            conn,
-                            extensions: Extensions::new(),
-                            birth: now,
-                            id,
-                        },
-                        idle_start: now,
-                    };
-                    internals.conns.push(conn);
-                    internals.pending_conns -= 1;
-                    internals.num_conns += 1;
-                    shared.cond.notify_one();
-                }
-                Err(err) => {
-                    shared.internals.lock().last_error = Some(err.to_string());
-                    shared.config.error_handler.handle_error(err);
-                    let delay = cmp::max(Duration::from_millis(200), delay);
-                    let delay = cmp::min(shared.config.connection_timeout / 2, delay * 2);
-                    inner(delay, &shared);
-                }
-            }
-        });
-    }
+fn retain_conns<M>(shared: &Arc<SharedPool<M>>, f: Arc<RetainFn<M::Connection>>)
+where
+    M: ManageConnection,
+{
+    shared.retains.push(f.clone());
+
+    let conns = shared
+        .drain_idle_where(|idle| !f(&idle.conn.conn, &idle.conn.extensions))
+        .into_iter()
+        .map(|idle| (idle.conn, ReleaseReason::Rejected))
+        .collect();
+    drop_conns(shared, shared.internals.lock(), conns);
 }
 
-fn reap_connections<M>(shared: &Weak<SharedPool<M>>)
+// Closes a connection which is no longer counted by the pool.
+fn release_conn<M>(shared: &SharedPool<M>, conn: Conn<M::Connection>, reason: ReleaseReason)
 where
     M: ManageConnection,
 {
-    let shared = match shared.upgrade() {
-        Some(shared) => shared,
-        None => return,
+    let event = ReleaseEvent {
+        id: conn.id,
+        age: conn.birth.elapsed(),
+        reason,
     };
+    shared.retains.release(conn.retain_epoch);
+    shared.generations.release(conn.generation);
+    shared.config.event_handler.handle_release(event);
+    shared.config.connection_customizer.on_release(conn.conn);
+}
 
-    let mut old = Vec::with_capacity(shared.config.max_size as usize);
-    let mut to_drop = vec![];
-
+// Reports the error which made a connection unusable and discards it.
+//
+// Returns the number of replacement connections requested.
+fn discard_conn<M>(shared: &Arc<SharedPool<M>>, conn: Conn<M::Connection>, err: M::Error) -> u32
+where
+    M: ManageConnection,
+{
+    let msg = err.to_string();
+    shared.config.error_handler.handle_error(err);
     let mut internals = shared.internals.lock();
-    mem::swap(&mut old, &mut internals.conns);
-    let now = Instant::now();
-    for conn in old {
-        let mut reap = false;
-        if let Some(timeout) = shared.config.idle_timeout {
-            reap |= now - conn.idle_start >= timeout;
-        }
-        if let Some(lifetime) = shared.config.max_lifetime {
-            reap |= now - conn.conn.birth >= lifetime;
-        }
-        if reap {
-            to_drop.push(conn.conn);
-        } else {
-            internals.conns.push(conn);
-        }
+    internals.record_error(msg);
+    drop_conns(shared, internals, vec![(conn, ReleaseReason::Invalid)])
+}
+
+// Validates a connection, treating a check which outlives `deadline` as a
+// failure. The error is `None` if the check timed out.
+fn validate_conn<M>(
+    shared: &SharedPool<M>,
+    conn: &mut M::Connection,
+    deadline: Instant,
+) -> Result<(), Option<M::Error>>
+where
+    M: ManageConnection,
+{
+    match shared.manager.is_valid_with_deadline(conn, deadline) {
+        Ok(()) if Instant::now() >= deadline => Err(None),
+        Ok(()) => Ok(()),
+        Err(e) => Err(Some(e)),
     }
-    drop_conns(&shared, internals, to_drop);
 }
 
-/// A generic connection pool.
-pub struct Pool<M>(Arc<SharedPool<M>>)
+// Like `discard_conn`, for connections which failed `validate_conn`.
+fn discard_invalid_conn<M>(
+    shared: &Arc<SharedPool<M>>,
+    conn: Conn<M::Connection>,
+    err: Option<M::Error>,
+) -> u32
 where
-    M: ManageConnection;
+    M: ManageConnection,
+{
+    match err {
+        Some(err) => discard_conn(shared, conn, err),
+        None => {
+            let mut internals = shared.internals.lock();
+            internals.record_error("timed out validating a connection".to_owned());
+            drop_conns(shared, internals, vec![(conn, ReleaseReason::Invalid)])
+        }
+    }
+}
 
-/// Returns a new `Pool` referencing the same state as `self`.
-impl<M> Clone for Pool<M>
+// Returns the number of new connections requested.
+fn establish_idle_connections<M>(shared: &Arc<SharedPool<M>>, internals: &mut PoolInternals) -> u32
 where
     M: ManageConnection,
 {
-    fn clone(&self) -> Pool<M> {
-        Pool(self.0.clone())
+    // connections which are already on their way count towards the minimum
+    let pending = shared.pending_conns();
+    let idle = shared.idle_conns() + pending;
+    for _ in idle..shared.min_idle() {
+        add_connection(shared, internals);
     }
+    shared.pending_conns() - pending
 }
 
-impl<M> fmt::Debug for Pool<M>
+// Like `establish_idle_connections`, but avoids taking the lock when there's
+// clearly nothing to do.
+fn maybe_establish_idle_connections<M>(shared: &Arc<SharedPool<M>>)
 where
-    M: ManageConnection + fmt::Debug,
+    M: ManageConnection,
 {
-    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("Pool")
-            .field("state", &self.state())
-            .field("config", &self.0.config)
-            .field("manager", &self.0.manager)
-            .finish()
+    if shared.idle_conns() + shared.pending_conns() < shared.min_idle() && !shared.at_capacity() {
+        establish_idle_connections(shared, &mut shared.internals.lock());
     }
 }
 
-impl<M> Pool<M>
+// `internals` is unused, but ensures the caller holds the lock.
+fn add_connection<M>(shared: &Arc<SharedPool<M>>, _: &mut PoolInternals)
 where
     M: ManageConnection,
 {
-    /// Creates a new connection pool with a default configuration.
-    pub fn new(manager: M) -> Result<Pool<M>, Error> {
-        Pool::builder().build(manager)
+    if shared.config.thread_pool.is_none() {
+        return;
     }
 
-    /// Returns a builder type to configure a new pool.
-    pub fn builder() -> Builder<M> {
-        Builder::new()
+    if shared.at_capacity() {
+        return;
     }
 
-    // for testing
-    fn new_inner(
-        config: Config<M::Connection, M::Error>,
-        manager: M,
-        reaper_rate: Duration,
-    ) -> Pool<M> {
-        let internals = PoolInternals {
-            conns: Vec::with_capacity(config.max_size as usize),
-            num_conns: 0,
-            pending_conns: 0,
-            last_error: None,
+    if let Some(ref limit) = shared.config.connection_limit {
+        if !limit.acquire() {
+            return;
+        }
+    }
+
+    shared.pending_conns.fetch_add(1, Ordering::SeqCst);
+    connect_in_background(shared, Duration::from_secs(0));
+}
+
+// Establishes a connection already counted in `pending_conns` on the thread
+// pool, retrying until it succeeds or the pool is dropped.
+fn connect_in_background<M>(shared: &Arc<SharedPool<M>>, delay: Duration)
+where
+    M: ManageConnection,
+{
+    let thread_pool = match shared.config.thread_pool {
+        Some(ref thread_pool) => thread_pool,
+        None => return,
+    };
+
+    let new_shared = Arc::downgrade(shared);
+    thread_pool.execute_after(delay, move || {
+        let shared = match new_shared.upgrade() {
+            Some(shared) => shared,
+            None => return,
         };
 
-        let shared = Arc::new(SharedPool {
-            config,
-            manager,
-            internals: Mutex::new(internals),
-            cond: Condvar::new(),
-        });
+        // asynchronous connections are driven to completion without tying
+        // up this thread, and come back through `background_connect_done`
+        if let Some(future) = shared.manager.connect_async() {
+            future::spawn_connect(&shared, future, delay);
+            return;
+        }
 
-        establish_idle_connections(&shared, &mut shared.internals.lock());
+        let done = Arc::new(AtomicBool::new(false));
+        let abandon = {
+            let done = done.clone();
+            move || !done.swap(true, Ordering::SeqCst)
+        };
+        schedule_connect_timeout(&shared, delay, abandon);
 
-        if shared.config.max_lifetime.is_some() || shared.config.idle_timeout.is_some() {
-            let s = Arc::downgrade(&shared);
-            shared
-                .config
-                .thread_pool
-                .execute_at_fixed_rate(reaper_rate, reaper_rate, move || reap_connections(&s));
+        let result = new_connection(&shared, shared.connect_deadline());
+        if !done.swap(true, Ordering::SeqCst) {
+            background_connect_done(&shared, result, delay);
+        } else {
+            // the attempt has already been given up on and retried
+            match result {
+                Ok(conn) => release_conn(&shared, conn, ReleaseReason::ConnectTimeout),
+                Err(err) => shared.config.error_handler.handle_error(err),
+            }
         }
+    });
+}
 
-        Pool(shared)
-    }
+// Connect timeouts can't be run by a pool's own workers, since they may all be
+// stuck in the attempts being timed out. They are cheap, so a single thread
+// is shared by every pool.
+static CONNECT_TIMER: Mutex<Option<Arc<ScheduledThreadPool>>> = parking_lot::const_mutex(None);
+
+fn connect_timer() -> Arc<ScheduledThreadPool> {
+    #[allow(deprecated)]
+    CONNECT_TIMER
+        .lock()
+        .get_or_insert_with(|| Arc::new(ScheduledThreadPool::with_name("r2d2-timer-{}", 1)))
+        .clone()
+}
 
-    fn wait_for_initialization(&self) -> Result<(), Error> {
-        let end = Instant::now() + self.0.config.connection_timeout;
-        let mut internals = self.0.internals.lock();
+// Gives up on a background connection attempt if it is still running once the
+// connect timeout has passed, and retries it. `abandon` returns false if the
+// attempt has already completed.
+fn schedule_connect_timeout<M, F>(shared: &Arc<SharedPool<M>>, delay: Duration, abandon: F)
+where
+    M: ManageConnection,
+    F: FnOnce() -> bool + Send + 'static,
+{
+    let timeout = match shared.config.connect_timeout {
+        Some(timeout) if shared.config.thread_pool.is_some() => timeout,
+        _ => return,
+    };
 
-        let initial_size = self.0.config.min_idle.unwrap_or(self.0.config.max_size);
+    let weak = Arc::downgrade(shared);
+    connect_timer().execute_after(timeout, move || {
+        let shared = match weak.upgrade() {
+            Some(shared) => shared,
+            None => return,
+        };
 
-        while internals.num_conns != initial_size {
-            if self.0.cond.wait_until(&mut internals, end).timed_out() {
-                return Err(Error(internals.last_error.take()));
-            }
+        if abandon() {
+            let msg = "timed out establishing a connection".to_owned();
+            shared.internals.lock().connect_failed(msg);
+            let delay = next_retry_delay(&shared, delay);
+            connect_in_background(&shared, delay);
         }
+    });
+}
 
-        Ok(())
+fn background_connect_done<M>(
+    shared: &Arc<SharedPool<M>>,
+    result: Result<Conn<M::Connection>, M::Error>,
+    delay: Duration,
+) where
+    M: ManageConnection,
+{
+    match result {
+        // the attempt started before `invalidate_all`, so start over
+        Ok(conn) if shared.generations.is_invalid(conn.generation) => {
+            release_conn(shared, conn, ReleaseReason::Invalidated);
+            connect_in_background(shared, Duration::from_secs(0));
+        }
+        Ok(conn) => {
+            let mut internals = shared.internals.lock();
+            internals.connect_succeeded();
+            shared.push_idle(conn);
+            shared.pending_conns.fetch_sub(1, Ordering::SeqCst);
+            shared.num_conns.fetch_add(1, Ordering::SeqCst);
+            shared.notify_locked();
+            drop(internals);
+            finish_recycle(shared);
+        }
+        Err(err) => {
+            shared.internals.lock().connect_failed(err.to_string());
+            shared.config.error_handler.handle_error(err);
+            let delay = next_retry_delay(shared, delay);
+            connect_in_background(shared, delay);
+        }
     }
+}
 
-    /// Retrieves a connection from the pool.
-    ///
-    /// Waits for at most the configured connection timeout before returning an
-    /// error.
-    pub fn get(&self) -> Result<PooledConnection<M>, Error> {
-        self.get_timeout(self.0.config.connection_timeout)
-    }
+fn next_retry_delay<M>(shared: &SharedPool<M>, delay: Duration) -> Duration
+where
+    M: ManageConnection,
+{
+    let delay = cmp::max(Duration::from_millis(200), delay);
+    cmp::min(shared.runtime().connection_timeout / 2, delay * 2)
+}
+
+// Opens and customizes a new connection without touching the pool's
+// bookkeeping. The caller is responsible for `pending_conns` and `num_conns`.
+fn new_connection<M>(
+    shared: &SharedPool<M>,
+    deadline: Instant,
+) -> Result<Conn<M::Connection>, M::Error>
+where
+    M: ManageConnection,
+{
+    let generation = shared.generations.current();
+    let conn = shared.manager.connect_with_deadline(deadline)?;
+    init_connection(shared, conn, generation)
+}
+
+// Customizes a connection which has just been opened by the manager.
+// `generation` is the pool's generation when the attempt started.
+fn init_connection<M>(
+    shared: &SharedPool<M>,
+    mut conn: M::Connection,
+    generation: u64,
+) -> Result<Conn<M::Connection>, M::Error>
+where
+    M: ManageConnection,
+{
+    shared.config.connection_customizer.on_acquire(&mut conn)?;
+
+    let id = CONNECTION_ID.fetch_add(1, Ordering::Relaxed) as u64;
+
+    let event = AcquireEvent { id };
+    shared.config.event_handler.handle_acquire(event);
+    shared.generations.register(generation);
+
+    Ok(Conn {
+        conn,
+        extensions: Extensions::new(),
+        birth: Instant::now(),
+        id,
+        use_count: 0,
+        retain_epoch: shared.retains.register(),
+        generation,
+    })
+}
+
+fn reap_connections<M>(shared: &Weak<SharedPool<M>>)
+where
+    M: ManageConnection,
+{
+    let shared = match shared.upgrade() {
+        Some(shared) => shared,
+        None => return,
+    };
+
+    let internals = shared.internals.lock();
+    reap(&shared, internals);
+}
+
+fn reap<M>(shared: &Arc<SharedPool<M>>, mut internals: MutexGuard<PoolInternals>)
+where
+    M: ManageConnection,
+{
+    let to_drop = expired_conns(shared, &mut internals);
+    drop_conns(shared, internals, to_drop);
+}
+
+fn expired_conns<M>(
+    shared: &SharedPool<M>,
+    internals: &mut PoolInternals,
+) -> Vec<(Conn<M::Connection>, ReleaseReason)>
+where
+    M: ManageConnection,
+{
+    let now = Instant::now();
+    internals.last_reap = now;
+    let mut expired = vec![];
+
+    let runtime = shared.runtime();
+
+    // connections cached by threads which have exited are up for grabs
+    if let Some(ref cache) = shared.cache {
+        for conn in cache.drain_abandoned() {
+            shared.idle.push(conn);
+        }
+    }
+
+    if let Some(lifetime) = runtime.max_lifetime {
+        let conns = shared.drain_idle_where(|conn| now - conn.conn.birth >= lifetime);
+        expired.extend(
+            conns
+                .into_iter()
+                .map(|conn| (conn.conn, ReleaseReason::MaxLifetime)),
+        );
+    }
+
+    // closing idle connections below min_idle would just cause them to be
+    // replaced right away
+    if let Some(timeout) = runtime.idle_timeout {
+        let mut surplus = shared.idle_conns().saturating_sub(shared.min_idle());
+        let conns = shared.drain_idle_where(|conn| {
+            if surplus > 0 && now - conn.idle_start >= timeout {
+                surplus -= 1;
+                true
+            } else {
+                false
+            }
+        });
+        expired.extend(
+            conns
+                .into_iter()
+                .map(|conn| (conn.conn, ReleaseReason::IdleTimeout)),
+        );
+    }
+
+    expired
+}
+
+// Starts or stops the reaper depending on whether connections can currently
+// expire.
+fn update_reaper<M>(shared: &Arc<SharedPool<M>>)
+where
+    M: ManageConnection,
+{
+    let thread_pool = match shared.config.thread_pool {
+        Some(ref thread_pool) if shared.config.automatic_reaping => thread_pool,
+        _ => return,
+    };
+
+    let mut reaper = shared.reaper.lock();
+    if shared.runtime().expires_conns() {
+        if reaper.is_none() {
+            let s = Arc::downgrade(shared);
+            let reaper_rate = shared.config.reaper_rate;
+            *reaper = Some(thread_pool.execute_at_fixed_rate(
+                reaper_rate,
+                reaper_rate,
+                move || reap_connections(&s),
+            ));
+        }
+    } else if let Some(reaper) = reaper.take() {
+        reaper.cancel();
+    }
+}
+
+// Replaces a single connection from before the current `Pool::recycle_all`,
+// rescheduling itself until there are none left.
+fn recycle_conns<M>(shared: &Weak<SharedPool<M>>)
+where
+    M: ManageConnection,
+{
+    let shared = match shared.upgrade() {
+        Some(shared) => shared,
+        None => return,
+    };
+
+    let (below, interval) = {
+        let mut recycle = shared.recycle.lock();
+        let (below, interval) = match *recycle {
+            Some(Recycle {
+                below, interval, ..
+            }) => (below, interval),
+            None => return,
+        };
+        if shared
+            .generations
+            .oldest()
+            .map_or(true, |oldest| oldest >= below)
+        {
+            *recycle = None;
+            return;
+        }
+        (below, interval)
+    };
+
+    if let Some(ref cache) = shared.cache {
+        for conn in cache.drain_where(|idle| idle.conn.generation < below) {
+            shared.idle.push(conn);
+        }
+    }
+
+    // Connections which are checked out are picked up once they're returned.
+    // The old connection is set aside until `finish_recycle` closes it once
+    // its replacement is ready, so it can't be checked out in the meantime
+    // and leave the pool over its maximum size.
+    let mut recycle = shared.recycle.lock();
+    if let Some(ref mut recycle) = *recycle {
+        if recycle.replacing.is_none() {
+            if let Some(old) = shared.idle.take(|idle| idle.conn.generation < below) {
+                let start = shared
+                    .config
+                    .connection_limit
+                    .as_ref()
+                    .map_or(true, |limit| limit.acquire());
+                if start {
+                    recycle.replacing = Some(old.conn);
+                    let _internals = shared.internals.lock();
+                    shared.pending_conns.fetch_add(1, Ordering::SeqCst);
+                    connect_in_background(&shared, Duration::from_secs(0));
+                } else {
+                    shared.idle.push(old);
+                    shared.notify_waiter();
+                }
+            }
+        }
+    }
+    drop(recycle);
+
+    if let Some(ref thread_pool) = shared.config.thread_pool {
+        let weak = Arc::downgrade(&shared);
+        thread_pool.execute_after(interval, move || recycle_conns(&weak));
+    }
+}
+
+// Closes a connection from before `Pool::recycle_all` once the replacement
+// requested by `recycle_conns` has been established.
+fn finish_recycle<M>(shared: &Arc<SharedPool<M>>)
+where
+    M: ManageConnection,
+{
+    let old = match *shared.recycle.lock() {
+        Some(ref mut recycle) => recycle.replacing.take(),
+        None => None,
+    };
+
+    if let Some(old) = old {
+        let conns = vec![(old, ReleaseReason::Recycled)];
+        drop_conns(shared, shared.internals.lock(), conns);
+    }
+}
+
+// In synchronous mode there is no reaper thread, so connections are reaped as
+// the pool is used instead.
+fn reap_if_due<M>(shared: &Arc<SharedPool<M>>)
+where
+    M: ManageConnection,
+{
+    if shared.config.thread_pool.is_some()
+        || !shared.config.automatic_reaping
+        || !shared.runtime().expires_conns()
+    {
+        return;
+    }
+
+    let internals = shared.internals.lock();
+    if internals.last_reap.elapsed() >= shared.config.reaper_rate {
+        reap(shared, internals);
+    }
+}
+
+/// A generic connection pool.
+pub struct Pool<M>(Arc<SharedPool<M>>)
+where
+    M: ManageConnection;
+
+/// Returns a new `Pool` referencing the same state as `self`.
+impl<M> Clone for Pool<M>
+where
+    M: ManageConnection,
+{
+    fn clone(&self) -> Pool<M> {
+        Pool(self.0.clone())
+    }
+}
+
+impl<M> fmt::Debug for Pool<M>
+where
+    M: ManageConnection + fmt::Debug,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Pool")
+            .field("state", &self.state())
+            .field("config", &self.0.config)
+            .field("manager", &self.0.manager)
+            .finish()
+    }
+}
+
+impl<M> Pool<M>
+where
+    M: ManageConnection,
+{
+    /// Creates a new connection pool with a default configuration.
+    pub fn new(manager: M) -> Result<Pool<M>, Error> {
+        Pool::builder().build(manager)
+    }
+
+    /// Returns a builder type to configure a new pool.
+    pub fn builder() -> Builder<M> {
+        Builder::new()
+    }
+
+    fn new_inner(config: Config<M::Connection, M::Error>, manager: M) -> Pool<M> {
+        let internals = PoolInternals {
+            last_error: None,
+            last_reap: Instant::now(),
+            last_failure: None,
+            connect_failures: 0,
+            last_connect: None,
+        };
+
+        let holders = if config.detect_reentrant_checkout {
+            Some(Holders::new())
+        } else {
+            None
+        };
+
+        let cache = if config.thread_cache {
+            Some(ThreadCache::new())
+        } else {
+            None
+        };
+
+        let shared = Arc::new(SharedPool {
+            idle: IdleConns::new(config.idle_shards, config.max_size),
+            config,
+            manager,
+            internals: Mutex::new(internals),
+            cond: Condvar::new(),
+            num_conns: AtomicU32::new(0),
+            pending_conns: AtomicU32::new(0),
+            waiters: AtomicU32::new(0),
+            batch_waiters: AtomicU32::new(0),
+            reaper: Mutex::new(None),
+            retains: Retains::new(),
+            generations: Generations::new(),
+            recycle: Mutex::new(None),
+            holders,
+            cache,
+        });
+
+        shared.manager.attach(Evictor(Arc::downgrade(&shared)));
+        establish_idle_connections(&shared, &mut shared.internals.lock());
+        update_reaper(&shared);
+
+        Pool(shared)
+    }
+
+    fn wait_for_initialization(&self) -> Result<(), Error> {
+        let end = Instant::now() + self.0.runtime().connection_timeout;
+        let initial_size = self.0.config.min_idle.unwrap_or(self.0.config.max_size);
+
+        if self.0.config.thread_pool.is_none() {
+            return self.initialize_inline(initial_size, end);
+        }
 
-    /// Retrieves a connection from the pool, waiting for at most `timeout`
-    ///
-    /// The given timeout will be used instead of the configured connection
-    /// timeout.
-    pub fn get_timeout(&self, timeout: Duration) -> Result<PooledConnection<M>, Error> {
-        let start = Instant::now();
-        let end = start + timeout;
         let mut internals = self.0.internals.lock();
 
-        loop {
-            match self.try_get_inner(internals) {
+        while self.0.num_conns() != initial_size {
+            if self.0.cond.wait_until(&mut internals, end).timed_out() {
+                return Err(Error(ErrorKind::Timeout(internals.last_error.take())));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn initialize_inline(&self, initial_size: u32, end: Instant) -> Result<(), Error> {
+        let mut delay = Duration::from_secs(0);
+
+        while self.0.num_conns() < initial_size {
+            let deadline = cmp::min(end, self.0.connect_deadline());
+            match new_connection(&self.0, deadline) {
                 Ok(conn) => {
-                    let event = CheckoutEvent {
-                        id: conn.conn.as_ref().unwrap().id,
-                        duration: start.elapsed(),
-                    };
-                    self.0.config.event_handler.handle_checkout(event);
-                    return Ok(conn);
+                    let mut internals = self.0.internals.lock();
+                    internals.connect_succeeded();
+                    self.0.push_idle(conn);
+                    self.0.num_conns.fetch_add(1, Ordering::SeqCst);
+                }
+                Err(err) => {
+                    let msg = err.to_string();
+                    self.0.config.error_handler.handle_error(err);
+
+                    delay = next_retry_delay(&self.0, delay);
+                    let now = Instant::now();
+                    if now + delay >= end {
+                        return Err(Error(ErrorKind::Timeout(Some(msg))));
+                    }
+                    self.0.internals.lock().connect_failed(msg);
+                    thread::sleep(delay);
                 }
-                Err(i) => internals = i,
             }
+        }
 
-            add_connection(&self.0, &mut internals);
+        Ok(())
+    }
 
-            if self.0.cond.wait_until(&mut internals, end).timed_out() {
+    /// Retrieves a connection from the pool.
+    ///
+    /// Waits for at most the configured connection timeout before returning an
+    /// error.
+    pub fn get(&self) -> Result<PooledConnection<M>, Error> {
+        self.get_timeout(self.0.runtime().connection_timeout)
+    }
+
+    /// Retrieves a connection from the pool, waiting for at most `timeout`
+    ///
+    /// The given timeout will be used instead of the configured connection
+    /// timeout.
+    pub fn get_timeout(&self, timeout: Duration) -> Result<PooledConnection<M>, Error> {
+        self.get_inner(timeout, None)
+    }
+
+    /// Retrieves a connection from the pool, preferring an idle connection
+    /// whose extensions match `affinity`.
+    ///
+    /// If no idle connection matches, any idle connection is returned, and
+    /// otherwise a new one is waited for as with `get`. This can be used to
+    /// avoid redoing session setup which has been recorded in a connection's
+    /// extensions, such as the selected schema.
+    ///
+    /// Waits for at most the configured connection timeout before returning an
+    /// error.
+    pub fn get_with_affinity<F>(&self, affinity: F) -> Result<PooledConnection<M>, Error>
+    where
+        F: Fn(&Extensions) -> bool,
+    {
+        self.get_timeout_with_affinity(affinity, self.0.runtime().connection_timeout)
+    }
+
+    /// Retrieves a connection from the pool, preferring an idle connection
+    /// whose extensions match `affinity` and waiting for at most `timeout`.
+    ///
+    /// The given timeout will be used instead of the configured connection
+    /// timeout.
+    pub fn get_timeout_with_affinity<F>(
+        &self,
+        affinity: F,
+        timeout: Duration,
+    ) -> Result<PooledConnection<M>, Error>
+    where
+        F: Fn(&Extensions) -> bool,
+    {
+        self.get_inner(timeout, Some(&affinity))
+    }
+
+    fn get_inner(
+        &self,
+        timeout: Duration,
+        affinity: Option<&dyn Fn(&Extensions) -> bool>,
+    ) -> Result<PooledConnection<M>, Error> {
+        self.check_reentrant(1)?;
+
+        let start = Instant::now();
+        let end = start + timeout;
+        let mut delay = Duration::from_secs(0);
+
+        reap_if_due(&self.0);
+
+        loop {
+            if let Some(conn) = self.try_get_inner(affinity, Some(end)) {
+                let event = CheckoutEvent {
+                    id: conn.conn.as_ref().unwrap().id,
+                    duration: start.elapsed(),
+                };
+                self.0.config.event_handler.handle_checkout(event);
+                return Ok(conn);
+            }
+
+            let mut internals = self.0.internals.lock();
+
+            let mut wait_end = end;
+            if self.0.config.thread_pool.is_some() {
+                add_connection(&self.0, &mut internals);
+            } else if !self.0.at_capacity() {
+                self.0.pending_conns.fetch_add(1, Ordering::SeqCst);
+                drop(internals);
+                let deadline = cmp::min(end, self.0.connect_deadline());
+                let result = new_connection(&self.0, deadline);
+                internals = self.0.internals.lock();
+                self.0.pending_conns.fetch_sub(1, Ordering::SeqCst);
+
+                match result {
+                    Ok(mut conn) => {
+                        internals.connect_succeeded();
+                        self.0.num_conns.fetch_add(1, Ordering::SeqCst);
+                        drop(internals);
+
+                        let customizer = &self.0.config.connection_customizer;
+                        if let Err(e) = customizer.on_checkout(&mut conn.conn) {
+                            discard_conn(&self.0, conn, e);
+                            delay = next_retry_delay(&self.0, delay);
+                            wait_end = cmp::min(end, Instant::now() + delay);
+                            internals = self.0.internals.lock();
+                        } else {
+                            let event = CheckoutEvent {
+                                id: conn.id,
+                                duration: start.elapsed(),
+                            };
+                            self.0.config.event_handler.handle_checkout(event);
+                            return Ok(self.check_out(conn, Duration::from_secs(0)));
+                        }
+                    }
+                    Err(err) => {
+                        internals.connect_failed(err.to_string());
+                        drop(internals);
+                        self.0.config.error_handler.handle_error(err);
+                        delay = next_retry_delay(&self.0, delay);
+                        wait_end = cmp::min(end, Instant::now() + delay);
+                        internals = self.0.internals.lock();
+                    }
+                }
+            }
+
+            // Register before checking the idle list so that a connection
+            // returned in the meantime either shows up here or wakes us.
+            self.0.waiters.fetch_add(1, Ordering::SeqCst);
+            self.0.spill_cached(&mut internals);
+            let timed_out = self.0.idle.is_empty()
+                && self.0.cond.wait_until(&mut internals, wait_end).timed_out();
+            self.0.waiters.fetch_sub(1, Ordering::SeqCst);
+
+            if timed_out && Instant::now() >= end {
+                let event = TimeoutEvent { timeout };
+                self.0.config.event_handler.handle_timeout(event);
+
+                return Err(Error(ErrorKind::Timeout(internals.last_error.take())));
+            }
+        }
+    }
+
+    /// Retrieves `n` connections from the pool at once, waiting for at most
+    /// `timeout`.
+    ///
+    /// The connections are reserved together, so either all of them are
+    /// returned or none are. Unlike calling `get` repeatedly, this can't
+    /// deadlock with other threads each holding some of the connections they
+    /// need while waiting for the rest.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is greater than the pool's maximum size.
+    pub fn get_many(&self, n: u32, timeout: Duration) -> Result<Vec<PooledConnection<M>>, Error> {
+        assert!(
+            n <= self.0.config.max_size,
+            "n must be no larger than max_size"
+        );
+        self.check_reentrant(n)?;
+
+        let start = Instant::now();
+        let end = start + timeout;
+        let mut delay = Duration::from_secs(0);
+
+        reap_if_due(&self.0);
+
+        loop {
+            let mut internals = self.0.internals.lock();
+
+            if let Some(reserved) = self.reserve(n) {
+                drop(internals);
+                maybe_establish_idle_connections(&self.0);
+
+                let reserved_at = Instant::now();
+                if let Some(prepared) = self.prepare_batch(reserved, end) {
+                    let conns = prepared
+                        .into_iter()
+                        .map(|conn| {
+                            let idle_duration = reserved_at.duration_since(conn.idle_start);
+                            let conn = self.check_out(conn.conn, idle_duration);
+                            let event = CheckoutEvent {
+                                id: conn.conn.as_ref().unwrap().id,
+                                duration: start.elapsed(),
+                            };
+                            self.0.config.event_handler.handle_checkout(event);
+                            conn
+                        })
+                        .collect();
+                    return Ok(conns);
+                }
+
+                // one of them was discarded and the rest given back, so try
+                // again
+                self.0.notify_waiter();
+                internals = self.0.internals.lock();
+            }
+
+            let mut wait_end = end;
+            if self.0.config.thread_pool.is_some() {
+                let available = self.0.idle_conns() + self.0.pending_conns();
+                for _ in available..n {
+                    add_connection(&self.0, &mut internals);
+                }
+            } else if self.0.idle_conns() < n && !self.0.at_capacity() {
+                self.0.pending_conns.fetch_add(1, Ordering::SeqCst);
+                drop(internals);
+                let deadline = cmp::min(end, self.0.connect_deadline());
+                let result = new_connection(&self.0, deadline);
+                internals = self.0.internals.lock();
+                self.0.pending_conns.fetch_sub(1, Ordering::SeqCst);
+
+                match result {
+                    Ok(conn) => {
+                        internals.connect_succeeded();
+                        self.0.num_conns.fetch_add(1, Ordering::SeqCst);
+                        self.0.push_idle(conn);
+                        continue;
+                    }
+                    Err(err) => {
+                        internals.connect_failed(err.to_string());
+                        drop(internals);
+                        self.0.config.error_handler.handle_error(err);
+                        delay = next_retry_delay(&self.0, delay);
+                        wait_end = cmp::min(end, Instant::now() + delay);
+                        internals = self.0.internals.lock();
+                    }
+                }
+            }
+
+            self.0.waiters.fetch_add(1, Ordering::SeqCst);
+            self.0.batch_waiters.fetch_add(1, Ordering::SeqCst);
+            self.0.spill_cached(&mut internals);
+            let timed_out = (self.0.idle.len() < n as usize)
+                && self.0.cond.wait_until(&mut internals, wait_end).timed_out();
+            self.0.batch_waiters.fetch_sub(1, Ordering::SeqCst);
+            self.0.waiters.fetch_sub(1, Ordering::SeqCst);
+
+            if timed_out && Instant::now() >= end {
                 let event = TimeoutEvent { timeout };
                 self.0.config.event_handler.handle_timeout(event);
 
-                return Err(Error(internals.last_error.take()));
+                return Err(Error(ErrorKind::Timeout(internals.last_error.take())));
             }
         }
     }
 
+    // Fails if the current thread would have to hold more than `max_size`
+    // connections for a checkout of `n` more to succeed.
+    fn check_reentrant(&self, n: u32) -> Result<(), Error> {
+        let holders = match self.0.holders {
+            Some(ref holders) => holders,
+            None => return Ok(()),
+        };
+
+        let held = holders.held();
+        if held + n <= self.0.config.max_size {
+            return Ok(());
+        }
+
+        Err(Error(ErrorKind::Reentrant {
+            held,
+            max_size: self.0.config.max_size,
+            n,
+        }))
+    }
+
+    // Takes `n` idle connections, or none if there aren't enough. The caller
+    // must hold the lock.
+    fn reserve(&self, n: u32) -> Option<Vec<IdleConn<M::Connection>>> {
+        if self.0.idle.len() < n as usize {
+            return None;
+        }
+
+        let mut reserved = Vec::with_capacity(n as usize);
+        while reserved.len() < n as usize {
+            match self.0.idle.pop() {
+                Some(conn) => reserved.push(conn),
+                // lost a race with a lock-free checkout
+                None => {
+                    for conn in reserved {
+                        self.0.idle.push(conn);
+                    }
+                    return None;
+                }
+            }
+        }
+
+        Some(reserved)
+    }
+
     /// Attempts to retrieve a connection from the pool if there is one
     /// available.
     ///
     /// Returns `None` if there are no idle connections available in the pool.
     /// This method will not block waiting to establish a new connection.
     pub fn try_get(&self) -> Option<PooledConnection<M>> {
-        self.try_get_inner(self.0.internals.lock()).ok()
+        self.try_get_inner(None, None)
     }
 
-    fn try_get_inner<'a>(
-        &'a self,
-        mut internals: MutexGuard<'a, PoolInternals<M::Connection>>,
-    ) -> Result<PooledConnection<M>, MutexGuard<'a, PoolInternals<M::Connection>>> {
+    // If `end` is set, gives up once it has passed while validating
+    // connections.
+    fn try_get_inner(
+        &self,
+        affinity: Option<&dyn Fn(&Extensions) -> bool>,
+        end: Option<Instant>,
+    ) -> Option<PooledConnection<M>> {
+        if let Some(conn) = self.0.cache.as_ref().and_then(ThreadCache::take) {
+            maybe_establish_idle_connections(&self.0);
+            if affinity.map_or(true, |affinity| affinity(&conn.conn.extensions)) {
+                if let Some(conn) = self.check_out_idle(conn, end) {
+                    return Some(conn);
+                }
+            } else {
+                self.0.idle.push(conn);
+            }
+        }
+
         loop {
-            if let Some(mut conn) = internals.conns.pop() {
-                establish_idle_connections(&self.0, &mut internals);
-                drop(internals);
+            let conn = match affinity {
+                Some(affinity) => self
+                    .0
+                    .idle
+                    .take(|conn| affinity(&conn.conn.extensions))
+                    .or_else(|| self.pop_idle()),
+                None => self.pop_idle(),
+            };
+            let conn = conn?;
+            maybe_establish_idle_connections(&self.0);
 
-                if self.0.config.test_on_check_out {
-                    if let Err(e) = self.0.manager.is_valid(&mut conn.conn.conn) {
-                        let msg = e.to_string();
-                        self.0.config.error_handler.handle_error(e);
-                        // FIXME we shouldn't have to lock, unlock, and relock here
-                        internals = self.0.internals.lock();
-                        internals.last_error = Some(msg);
-                        drop_conns(&self.0, internals, vec![conn.conn]);
-                        internals = self.0.internals.lock();
-                        continue;
+            match self.check_out_idle(conn, end) {
+                Some(conn) => return Some(conn),
+                None if end.map_or(false, |end| Instant::now() >= end) => return None,
+                None => {}
+            }
+        }
+    }
+
+    // Takes an idle connection, falling back to those cached by other threads
+    // if there are none in the shared list.
+    fn pop_idle(&self) -> Option<IdleConn<M::Connection>> {
+        self.0.idle.pop().or_else(|| {
+            if self.0.cache.as_ref().map_or(true, |cache| cache.len() == 0) {
+                return None;
+            }
+            self.0.spill_cached(&mut self.0.internals.lock());
+            self.0.idle.pop()
+        })
+    }
+
+    // Validates and customizes an idle connection, discarding it and returning
+    // `None` if either fails.
+    fn check_out_idle(
+        &self,
+        conn: IdleConn<M::Connection>,
+        end: Option<Instant>,
+    ) -> Option<PooledConnection<M>> {
+        let idle_duration = conn.idle_start.elapsed();
+        let conn = self.test_idle(conn, end)?;
+        let conn = self.prepare_idle(conn)?;
+        Some(self.check_out(conn.conn, idle_duration))
+    }
+
+    // Validates an idle connection if the pool is configured to, discarding
+    // it if that fails.
+    fn test_idle(
+        &self,
+        mut conn: IdleConn<M::Connection>,
+        end: Option<Instant>,
+    ) -> Option<IdleConn<M::Connection>> {
+        if self.0.runtime().test_on_check_out {
+            let mut deadline = self.0.validation_deadline();
+            if let Some(end) = end {
+                deadline = cmp::min(deadline, end);
+            }
+
+            if let Err(e) = validate_conn(&self.0, &mut conn.conn.conn, deadline) {
+                discard_invalid_conn(&self.0, conn.conn, e);
+                return None;
+            }
+        }
+
+        Some(conn)
+    }
+
+    // Runs the customizer's `on_checkout`, discarding the connection if it
+    // fails.
+    fn prepare_idle(&self, mut conn: IdleConn<M::Connection>) -> Option<IdleConn<M::Connection>> {
+        if let Err(e) = self
+            .0
+            .config
+            .connection_customizer
+            .on_checkout(&mut conn.conn.conn)
+        {
+            discard_conn(&self.0, conn.conn, e);
+            return None;
+        }
+
+        Some(conn)
+    }
+
+    // Validates and prepares all of the reserved connections for checkout. If
+    // any of them fails, it is discarded and the rest are returned to the idle
+    // list, resetting those which have already been prepared.
+    fn prepare_batch(
+        &self,
+        reserved: Vec<IdleConn<M::Connection>>,
+        end: Instant,
+    ) -> Option<Vec<IdleConn<M::Connection>>> {
+        let mut tested = Vec::with_capacity(reserved.len());
+        let mut reserved = reserved.into_iter();
+        for conn in reserved.by_ref() {
+            match self.test_idle(conn, Some(end)) {
+                Some(conn) => tested.push(conn),
+                None => {
+                    for conn in tested.into_iter().chain(reserved) {
+                        self.0.idle.push(conn);
                     }
+                    return None;
                 }
+            }
+        }
 
-                return Ok(PooledConnection {
-                    pool: self.clone(),
-                    checkout: Instant::now(),
-                    conn: Some(conn.conn),
-                });
-            } else {
-                return Err(internals);
+        let mut prepared = Vec::with_capacity(tested.len());
+        let mut tested = tested.into_iter();
+        for conn in tested.by_ref() {
+            match self.prepare_idle(conn) {
+                Some(conn) => prepared.push(conn),
+                None => {
+                    for mut conn in prepared {
+                        match self
+                            .0
+                            .config
+                            .connection_customizer
+                            .on_checkin(&mut conn.conn.conn)
+                        {
+                            Ok(()) => self.0.idle.push(conn),
+                            Err(e) => {
+                                discard_conn(&self.0, conn.conn, e);
+                            }
+                        }
+                    }
+                    for conn in tested {
+                        self.0.idle.push(conn);
+                    }
+                    return None;
+                }
             }
         }
+
+        Some(prepared)
+    }
+
+    fn check_out(
+        &self,
+        mut conn: Conn<M::Connection>,
+        idle_duration: Duration,
+    ) -> PooledConnection<M> {
+        conn.use_count += 1;
+        PooledConnection {
+            pool: self.clone(),
+            checkout: Instant::now(),
+            idle_duration,
+            holder: self.0.holders.as_ref().map(Holders::acquire),
+            conn: Some(conn),
+        }
     }
 
     fn put_back(&self, checkout: Instant, mut conn: Conn<M::Connection>) {
@@ -494,31 +1681,303 @@ where
         self.0.config.event_handler.handle_checkin(event);
 
         // This is specified to be fast, but call it before locking anyways
-        let broken = self.0.manager.has_broken(&mut conn.conn);
+        let max_uses = self.0.config.max_uses;
+        if self.0.manager.has_broken(&mut conn.conn) {
+            let conns = vec![(conn, ReleaseReason::Broken)];
+            drop_conns(&self.0, self.0.internals.lock(), conns);
+        } else if max_uses.map_or(false, |max_uses| conn.use_count >= max_uses) {
+            let conns = vec![(conn, ReleaseReason::MaxUses)];
+            drop_conns(&self.0, self.0.internals.lock(), conns);
+        } else if self.0.generations.is_invalid(conn.generation) {
+            let conns = vec![(conn, ReleaseReason::Invalidated)];
+            drop_conns(&self.0, self.0.internals.lock(), conns);
+        } else if self.0.recycle_on_checkin(&conn) {
+            let conns = vec![(conn, ReleaseReason::Recycled)];
+            drop_conns(&self.0, self.0.internals.lock(), conns);
+        } else if !self.0.retains.check(&mut conn) {
+            let conns = vec![(conn, ReleaseReason::Rejected)];
+            drop_conns(&self.0, self.0.internals.lock(), conns);
+        } else if let Err(e) = self
+            .0
+            .config
+            .connection_customizer
+            .on_checkin(&mut conn.conn)
+        {
+            discard_conn(&self.0, conn, e);
+        } else {
+            self.0.check_in_idle(conn);
+            reap_if_due(&self.0);
+        }
+    }
 
+    /// Closes all of the pool's current connections.
+    ///
+    /// Idle connections are closed immediately and replaced to satisfy the
+    /// minimum idle connection count, while connections which are currently
+    /// checked out are closed when they are returned to the pool. Connection
+    /// attempts already in progress are retried.
+    pub fn invalidate_all(&self) {
+        let generation = self.0.generations.invalidate();
+
+        let conns = self
+            .0
+            .drain_idle_where(|idle| idle.conn.generation < generation)
+            .into_iter()
+            .map(|idle| (idle.conn, ReleaseReason::Invalidated))
+            .collect();
+        drop_conns(&self.0, self.0.internals.lock(), conns);
+    }
+
+    /// Gradually replaces all of the pool's current connections.
+    ///
+    /// Up to `rate` connections are replaced per second, with each replacement
+    /// established before the connection it replaces is closed. Connections
+    /// which are checked out are replaced once they have been returned to the
+    /// pool. Calling this again while connections are being recycled restarts
+    /// the process at the new rate, covering all connections open at the time.
+    ///
+    /// Pools in synchronous mode have no thread to do this on, so their
+    /// connections are instead closed as they are returned to the pool, at
+    /// the same rate, and replaced when a new connection is next needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rate` is 0.
+    pub fn recycle_all(&self, rate: u32) {
+        assert!(rate > 0, "rate must be positive");
+
+        let interval = Duration::from_secs(1) / rate;
+        let below = self.0.generations.advance();
+        let running = {
+            let mut recycle = self.0.recycle.lock();
+            // a replacement which is still being established counts towards
+            // the new run
+            let replacing = recycle
+                .as_mut()
+                .and_then(|recycle| recycle.replacing.take());
+            recycle
+                .replace(Recycle {
+                    below,
+                    interval,
+                    replacing,
+                    last: None,
+                })
+                .is_some()
+        };
+
+        if let (false, Some(thread_pool)) = (running, &self.0.config.thread_pool) {
+            let shared = Arc::downgrade(&self.0);
+            thread_pool.execute(move || recycle_conns(&shared));
+        }
+    }
+
+    /// Closes connections for which the predicate returns false.
+    ///
+    /// Idle connections are checked immediately, and connections which are
+    /// currently checked out are checked when they are returned to the pool.
+    /// Connections established afterwards are not checked.
+    pub fn retain<F>(&self, f: F)
+    where
+        F: Fn(&M::Connection, &Extensions) -> bool + Send + Sync + 'static,
+    {
+        retain_conns(&self.0, Arc::new(f));
+    }
+
+    /// Performs a round of pool maintenance on the calling thread.
+    ///
+    /// Connections which have exceeded their idle timeout or maximum lifetime
+    /// are closed, each idle connection is checked via
+    /// `ManageConnection::is_valid`, and new connections are requested until
+    /// the pool's minimum idle connection count is satisfied. In synchronous
+    /// mode those connections are established before this method returns;
+    /// otherwise they are established in the background.
+    ///
+    /// This is intended for use alongside `Builder::automatic_reaping`, but
+    /// may be called at any time.
+    pub fn run_maintenance(&self) -> MaintenanceReport {
         let mut internals = self.0.internals.lock();
-        if broken {
-            drop_conns(&self.0, internals, vec![conn]);
-        } else {
-            let conn = IdleConn {
-                conn,
-                idle_start: Instant::now(),
+        let expired = expired_conns(&self.0, &mut internals);
+        let reaped_connections = expired.len() as u32;
+        let mut new_connections = drop_conns(&self.0, internals, expired);
+
+        let (invalid_connections, requested) = self.validate_idle_connections();
+        new_connections += requested;
+        new_connections += self.replenish_idle_connections();
+
+        MaintenanceReport {
+            reaped_connections,
+            invalid_connections,
+            new_connections,
+            _p: (),
+        }
+    }
+
+    fn validate_idle_connections(&self) -> (u32, u32) {
+        let mut checked = vec![];
+        let mut invalid = 0;
+        let mut requested = 0;
+
+        // cached connections are checked along with the rest
+        if let Some(ref cache) = self.0.cache {
+            for conn in cache.drain() {
+                self.0.idle.push(conn);
+            }
+        }
+
+        loop {
+            // Connections are checked one at a time so the rest of the idle
+            // list stays available to `get` while validation runs.
+            let mut conn = match self.0.idle.take(|c| !checked.contains(&c.conn.id)) {
+                Some(conn) => conn,
+                None => return (invalid, requested),
             };
-            internals.conns.push(conn);
-            self.0.cond.notify_one();
+
+            checked.push(conn.conn.id);
+            let deadline = self.0.validation_deadline();
+            match validate_conn(&self.0, &mut conn.conn.conn, deadline) {
+                Ok(()) => {
+                    self.0.idle.push(conn);
+                    self.0.notify_waiter();
+                }
+                Err(e) => {
+                    requested += discard_invalid_conn(&self.0, conn.conn, e);
+                    invalid += 1;
+                }
+            }
+        }
+    }
+
+    fn replenish_idle_connections(&self) -> u32 {
+        let mut internals = self.0.internals.lock();
+
+        if self.0.config.thread_pool.is_some() {
+            return establish_idle_connections(&self.0, &mut internals);
+        }
+
+        let mut opened = 0;
+        while self.0.idle_conns() < self.0.min_idle() && !self.0.at_capacity() {
+            self.0.pending_conns.fetch_add(1, Ordering::SeqCst);
+            drop(internals);
+            let result = new_connection(&self.0, self.0.connect_deadline());
+            internals = self.0.internals.lock();
+            self.0.pending_conns.fetch_sub(1, Ordering::SeqCst);
+
+            match result {
+                Ok(conn) => {
+                    internals.connect_succeeded();
+                    self.0.num_conns.fetch_add(1, Ordering::SeqCst);
+                    self.0.push_idle(conn);
+                    self.0.notify_locked();
+                    opened += 1;
+                }
+                Err(err) => {
+                    internals.connect_failed(err.to_string());
+                    drop(internals);
+                    self.0.config.error_handler.handle_error(err);
+                    break;
+                }
+            }
+        }
+
+        opened
+    }
+
+    /// Checks out a connection and runs the closure with it.
+    ///
+    /// If the closure returns an error for which `is_fatal` returns true, or
+    /// panics, the connection is closed rather than being returned to the
+    /// pool.
+    ///
+    /// Waits for at most the configured connection timeout for a connection
+    /// before returning an error.
+    pub fn with<T, E, F, C>(&self, f: F, is_fatal: C) -> Result<T, WithError<E>>
+    where
+        F: FnOnce(&mut M::Connection) -> Result<T, E>,
+        C: FnOnce(&E) -> bool,
+    {
+        self.with_timeout(self.0.runtime().connection_timeout, f, is_fatal)
+    }
+
+    /// Like `with`, but waits for at most `timeout` for a connection.
+    pub fn with_timeout<T, E, F, C>(
+        &self,
+        timeout: Duration,
+        f: F,
+        is_fatal: C,
+    ) -> Result<T, WithError<E>>
+    where
+        F: FnOnce(&mut M::Connection) -> Result<T, E>,
+        C: FnOnce(&E) -> bool,
+    {
+        let mut conn = self.get_timeout(timeout).map_err(WithError::Pool)?;
+
+        match panic::catch_unwind(AssertUnwindSafe(|| f(&mut conn))) {
+            Ok(Ok(value)) => Ok(value),
+            Ok(Err(e)) => {
+                if is_fatal(&e) {
+                    PooledConnection::discard(conn);
+                }
+                Err(WithError::Inner(e))
+            }
+            Err(payload) => {
+                PooledConnection::discard(conn);
+                panic::resume_unwind(payload)
+            }
         }
     }
 
     /// Returns information about the current state of the pool.
     pub fn state(&self) -> State {
-        let internals = self.0.internals.lock();
         State {
-            connections: internals.num_conns,
-            idle_connections: internals.conns.len() as u32,
+            connections: self.0.num_conns(),
+            idle_connections: self.0.idle_conns(),
+            _p: (),
+        }
+    }
+
+    /// Returns a report on whether the pool is currently able to hand out
+    /// connections.
+    pub fn health(&self) -> Health {
+        let internals = self.0.internals.lock();
+        let (last_error, last_error_at) = match internals.last_failure {
+            Some((ref msg, at)) => (Some(msg.clone()), Some(at)),
+            None => (None, None),
+        };
+
+        Health {
+            last_error,
+            last_error_at,
+            consecutive_connect_failures: internals.connect_failures,
+            since_last_connect: internals.last_connect.map(|at| at.elapsed()),
+            has_live_connection: self.0.num_conns() > 0,
+            min_idle_satisfied: self.0.idle_conns() >= self.0.min_idle(),
             _p: (),
         }
     }
 
+    /// Changes parts of the pool's configuration while it is running.
+    ///
+    /// The closure is passed the current configuration to modify. Existing
+    /// connections are kept, and the new values apply to all checkouts and
+    /// reaping from then on.
+    ///
+    /// Concurrent calls are run one at a time, but the pool can be used as
+    /// normal while the closure runs. The closure must not call `reconfigure`
+    /// itself.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the closure leaves a value which would cause the
+    /// corresponding `Builder` method to panic, in which case the
+    /// configuration is left unchanged.
+    pub fn reconfigure<F>(&self, f: F)
+    where
+        F: FnOnce(&mut RuntimeConfig),
+    {
+        self.0.config.runtime.update(f);
+        update_reaper(&self.0);
+    }
+
     /// Returns the configured maximum pool size.
     pub fn max_size(&self) -> u32 {
         self.0.config.max_size
@@ -531,46 +1990,157 @@ where
 
     /// Returns if the pool is configured to test connections on check out.
     pub fn test_on_check_out(&self) -> bool {
-        self.0.config.test_on_check_out
+        self.0.runtime().test_on_check_out
     }
 
     /// Returns the configured maximum connection lifetime.
     pub fn max_lifetime(&self) -> Option<Duration> {
-        self.0.config.max_lifetime
+        self.0.runtime().max_lifetime
     }
 
     /// Returns the configured idle connection timeout.
     pub fn idle_timeout(&self) -> Option<Duration> {
-        self.0.config.idle_timeout
+        self.0.runtime().idle_timeout
     }
 
     /// Returns the configured connection timeout.
     pub fn connection_timeout(&self) -> Duration {
-        self.0.config.connection_timeout
+        self.0.runtime().connection_timeout
+    }
+
+    /// Returns the configured connect timeout.
+    pub fn connect_timeout(&self) -> Option<Duration> {
+        self.0.config.connect_timeout
+    }
+
+    /// Returns the configured validation timeout.
+    pub fn validation_timeout(&self) -> Option<Duration> {
+        self.0.config.validation_timeout
+    }
+
+    /// Returns the configured maximum number of uses of a connection.
+    pub fn max_uses(&self) -> Option<u64> {
+        self.0.config.max_uses
     }
 }
 
 /// The error type returned by methods in this crate.
 #[derive(Debug)]
-pub struct Error(Option<String>);
+pub struct Error(ErrorKind);
+
+#[derive(Debug)]
+enum ErrorKind {
+    // with the last error encountered while connecting, if any
+    Timeout(Option<String>),
+    // see `Builder::detect_reentrant_checkout`
+    Reentrant { held: u32, max_size: u32, n: u32 },
+}
 
 impl fmt::Display for Error {
+    #[allow(deprecated)]
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.write_str(error::Error::description(self))?;
-        if let Some(ref err) = self.0 {
-            write!(fmt, ": {}", err)?;
+        match self.0 {
+            ErrorKind::Timeout(Some(ref err)) => write!(fmt, ": {}", err),
+            ErrorKind::Timeout(None) => Ok(()),
+            ErrorKind::Reentrant { held, max_size, n } => write!(
+                fmt,
+                ": the current thread already holds {} of the pool's {} connections, so \
+                 checking out {} more would wait forever",
+                held, max_size, n
+            ),
         }
-        Ok(())
     }
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
-        "timed out waiting for connection"
+        match self.0 {
+            ErrorKind::Timeout(_) => "timed out waiting for connection",
+            ErrorKind::Reentrant { .. } => "reentrant checkout would deadlock",
+        }
+    }
+}
+
+/// The error type returned by `Pool::with` and `Pool::with_timeout`.
+#[derive(Debug)]
+pub enum WithError<E> {
+    /// A connection could not be checked out of the pool.
+    Pool(Error),
+    /// An error returned by the closure.
+    Inner(E),
+}
+
+impl<E> fmt::Display for WithError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WithError::Pool(ref e) => fmt::Display::fmt(e, fmt),
+            WithError::Inner(ref e) => fmt::Display::fmt(e, fmt),
+        }
+    }
+}
+
+impl<E> error::Error for WithError<E>
+where
+    E: error::Error + 'static,
+{
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            WithError::Pool(ref e) => Some(e),
+            WithError::Inner(ref e) => Some(e),
+        }
+    }
+}
+
+/// A handle a manager can use to close connections in the pool it belongs
+/// to.
+///
+/// See `ManageConnection::attach`. It doesn't keep the pool alive.
+pub struct Evictor<M>(Weak<SharedPool<M>>)
+where
+    M: ManageConnection;
+
+impl<M> Clone for Evictor<M>
+where
+    M: ManageConnection,
+{
+    fn clone(&self) -> Evictor<M> {
+        Evictor(self.0.clone())
+    }
+}
+
+impl<M> fmt::Debug for Evictor<M>
+where
+    M: ManageConnection,
+{
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Evictor").finish()
+    }
+}
+
+impl<M> Evictor<M>
+where
+    M: ManageConnection,
+{
+    /// Closes connections for which the predicate returns false, as
+    /// `Pool::retain` does.
+    ///
+    /// Does nothing if the pool has been dropped.
+    pub fn retain<F>(&self, f: F)
+    where
+        F: Fn(&M::Connection, &Extensions) -> bool + Send + Sync + 'static,
+    {
+        if let Some(shared) = self.0.upgrade() {
+            retain_conns(&shared, Arc::new(f));
+        }
     }
 }
 
 /// Information about the state of a `Pool`.
+#[allow(clippy::manual_non_exhaustive)]
 pub struct State {
     /// The number of connections currently being managed by the pool.
     pub connections: u32,
@@ -588,6 +2158,68 @@ impl fmt::Debug for State {
     }
 }
 
+/// A summary of the work done by `Pool::run_maintenance`.
+#[allow(clippy::manual_non_exhaustive)]
+pub struct MaintenanceReport {
+    /// The number of connections closed because they exceeded their idle
+    /// timeout or maximum lifetime.
+    pub reaped_connections: u32,
+    /// The number of idle connections closed because they failed validation.
+    pub invalid_connections: u32,
+    /// The number of connections opened, or requested from the thread pool,
+    /// to satisfy the minimum idle connection count.
+    pub new_connections: u32,
+    _p: (),
+}
+
+impl fmt::Debug for MaintenanceReport {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MaintenanceReport")
+            .field("reaped_connections", &self.reaped_connections)
+            .field("invalid_connections", &self.invalid_connections)
+            .field("new_connections", &self.new_connections)
+            .finish()
+    }
+}
+
+/// A report on the health of a pool, returned by `Pool::health`.
+#[allow(clippy::manual_non_exhaustive)]
+pub struct Health {
+    /// The most recent error encountered while connecting to or validating
+    /// connections, even if the pool has since recovered.
+    pub last_error: Option<String>,
+    /// When `last_error` occurred.
+    pub last_error_at: Option<Instant>,
+    /// The number of connection attempts which have failed since the last
+    /// successful one.
+    pub consecutive_connect_failures: u32,
+    /// The time since a connection was last successfully established, or
+    /// `None` if none ever has been.
+    pub since_last_connect: Option<Duration>,
+    /// If the pool has at least one open connection, whether idle or checked
+    /// out.
+    pub has_live_connection: bool,
+    /// If the pool holds at least its minimum number of idle connections.
+    pub min_idle_satisfied: bool,
+    _p: (),
+}
+
+impl fmt::Debug for Health {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Health")
+            .field("last_error", &self.last_error)
+            .field("last_error_at", &self.last_error_at)
+            .field(
+                "consecutive_connect_failures",
+                &self.consecutive_connect_failures,
+            )
+            .field("since_last_connect", &self.since_last_connect)
+            .field("has_live_connection", &self.has_live_connection)
+            .field("min_idle_satisfied", &self.min_idle_satisfied)
+            .finish()
+    }
+}
+
 /// A smart pointer wrapping a connection.
 pub struct PooledConnection<M>
 where
@@ -595,6 +2227,9 @@ where
 {
     pool: Pool<M>,
     checkout: Instant,
+    idle_duration: Duration,
+    // the thread the connection is counted against, if tracked
+    holder: Option<thread::ThreadId>,
     conn: Option<Conn<M::Connection>>,
 }
 
@@ -613,7 +2248,10 @@ where
     M: ManageConnection,
 {
     fn drop(&mut self) {
-        self.pool.put_back(self.checkout, self.conn.take().unwrap());
+        if let Some(conn) = self.conn.take() {
+            self.release_holder();
+            self.pool.put_back(self.checkout, conn);
+        }
     }
 }
 
@@ -650,4 +2288,56 @@ where
     pub fn extensions_mut(this: &mut Self) -> &mut Extensions {
         &mut this.conn.as_mut().unwrap().extensions
     }
+
+    fn release_holder(&mut self) {
+        if let (Some(holders), Some(id)) = (&self.pool.0.holders, self.holder.take()) {
+            holders.release(id);
+        }
+    }
+
+    // Closes the connection rather than returning it to the pool.
+    fn discard(mut this: Self) {
+        let conn = this.conn.take().unwrap();
+        this.release_holder();
+        let event = CheckinEvent {
+            id: conn.id,
+            duration: this.checkout.elapsed(),
+        };
+        let shared = &this.pool.0;
+        shared.config.event_handler.handle_checkin(event);
+        drop_conns(
+            shared,
+            shared.internals.lock(),
+            vec![(conn, ReleaseReason::Broken)],
+        );
+    }
+
+    /// Returns the connection's ID, as reported in events.
+    pub fn id(this: &Self) -> u64 {
+        this.conn.as_ref().unwrap().id
+    }
+
+    /// Returns the amount of time since the connection was established.
+    pub fn age(this: &Self) -> Duration {
+        this.conn.as_ref().unwrap().birth.elapsed()
+    }
+
+    /// Returns the time at which the connection was checked out.
+    pub fn checked_out_at(this: &Self) -> Instant {
+        this.checkout
+    }
+
+    /// Returns the number of times the connection has been checked out of the
+    /// pool, including this one.
+    pub fn use_count(this: &Self) -> u64 {
+        this.conn.as_ref().unwrap().use_count
+    }
+
+    /// Returns the amount of time the connection spent idle in the pool
+    /// before it was checked out.
+    ///
+    /// This is zero for connections which were established for this checkout.
+    pub fn idle_duration_before_checkout(this: &Self) -> Duration {
+        this.idle_duration
+    }
 }
@@ -0,0 +1,105 @@
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::extensions::Extensions;
+use crate::Conn;
+
+pub type RetainFn<C> = dyn Fn(&C, &Extensions) -> bool + Send + Sync;
+
+struct Filter<C> {
+    epoch: u64,
+    f: Arc<RetainFn<C>>,
+}
+
+struct Inner<C> {
+    filters: Vec<Filter<C>>,
+    // the number of live connections last checked at each epoch
+    live: BTreeMap<u64, u32>,
+}
+
+/// The predicates passed to `Pool::retain` which connections checked out at
+/// the time still have to be checked against.
+///
+/// Each connection records the epoch it was last checked at, and each
+/// predicate the epoch it was added at. Predicates are dropped once every
+/// live connection has been checked against them.
+pub struct Retains<C> {
+    epoch: AtomicU64,
+    inner: Mutex<Inner<C>>,
+}
+
+impl<C> Retains<C> {
+    pub fn new() -> Retains<C> {
+        Retains {
+            epoch: AtomicU64::new(0),
+            inner: Mutex::new(Inner {
+                filters: vec![],
+                live: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Adds a predicate to check connections which are currently checked out
+    /// against once they are returned.
+    pub fn push(&self, f: Arc<RetainFn<C>>) {
+        let mut inner = self.inner.lock();
+        let epoch = self.epoch.load(Ordering::SeqCst) + 1;
+        inner.filters.push(Filter { epoch, f });
+        self.epoch.store(epoch, Ordering::SeqCst);
+    }
+
+    /// Returns the epoch to record for a new connection.
+    pub fn register(&self) -> u64 {
+        let mut inner = self.inner.lock();
+        let epoch = self.epoch.load(Ordering::SeqCst);
+        *inner.live.entry(epoch).or_insert(0) += 1;
+        epoch
+    }
+
+    /// Forgets about a connection which has been closed.
+    pub fn release(&self, epoch: u64) {
+        let mut inner = self.inner.lock();
+        inner.remove(epoch);
+        inner.prune();
+    }
+
+    /// Checks a connection against the predicates added since it was last
+    /// checked, returning false if any of them reject it.
+    pub fn check(&self, conn: &mut Conn<C>) -> bool {
+        if conn.retain_epoch == self.epoch.load(Ordering::SeqCst) {
+            return true;
+        }
+
+        let mut inner = self.inner.lock();
+        let retain = inner
+            .filters
+            .iter()
+            .filter(|filter| filter.epoch > conn.retain_epoch)
+            .all(|filter| (filter.f)(&conn.conn, &conn.extensions));
+
+        inner.remove(conn.retain_epoch);
+        conn.retain_epoch = self.epoch.load(Ordering::SeqCst);
+        *inner.live.entry(conn.retain_epoch).or_insert(0) += 1;
+        inner.prune();
+
+        retain
+    }
+}
+
+impl<C> Inner<C> {
+    fn remove(&mut self, epoch: u64) {
+        if let Some(count) = self.live.get_mut(&epoch) {
+            *count -= 1;
+            if *count == 0 {
+                self.live.remove(&epoch);
+            }
+        }
+    }
+
+    fn prune(&mut self) {
+        let oldest = self.live.keys().next().cloned().unwrap_or(u64::MAX);
+        self.filters.retain(|filter| filter.epoch > oldest);
+    }
+}
@@ -0,0 +1,288 @@
+use scheduled_thread_pool::ScheduledThreadPool;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::{Builder, ManageConnection};
+
+/// A deserializable pool configuration.
+///
+/// Every field is optional, with unset fields left at the `Builder`'s
+/// defaults. Durations are written in a human-friendly format such as
+/// `"30s"` or `"10m"`. Since the optional timeouts cannot be zero, a zero
+/// duration disables them instead, and a zero `max_uses` likewise disables
+/// that limit.
+///
+/// Requires the `serde` Cargo feature.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[allow(clippy::manual_non_exhaustive)]
+pub struct PoolConfig {
+    /// See `Builder::max_size`.
+    pub max_size: Option<u32>,
+    /// See `Builder::min_idle`.
+    pub min_idle: Option<u32>,
+    /// See `Builder::test_on_check_out`.
+    pub test_on_check_out: Option<bool>,
+    /// See `Builder::max_lifetime`.
+    #[serde(with = "humantime_serde")]
+    pub max_lifetime: Option<Duration>,
+    /// See `Builder::max_uses`.
+    pub max_uses: Option<u64>,
+    /// See `Builder::idle_timeout`.
+    #[serde(with = "humantime_serde")]
+    pub idle_timeout: Option<Duration>,
+    /// See `Builder::connection_timeout`.
+    #[serde(with = "humantime_serde")]
+    pub connection_timeout: Option<Duration>,
+    /// See `Builder::connect_timeout`.
+    #[serde(with = "humantime_serde")]
+    pub connect_timeout: Option<Duration>,
+    /// See `Builder::validation_timeout`.
+    #[serde(with = "humantime_serde")]
+    pub validation_timeout: Option<Duration>,
+    /// The number of threads in the pool's thread pool.
+    ///
+    /// Ignored if `synchronous` is set. See `Builder::thread_pool`.
+    pub worker_threads: Option<NonZeroUsize>,
+    /// See `Builder::synchronous`.
+    pub synchronous: Option<bool>,
+    /// See `Builder::automatic_reaping`.
+    pub automatic_reaping: Option<bool>,
+    /// See `Builder::idle_shards`.
+    pub idle_shards: Option<u32>,
+    /// See `Builder::detect_reentrant_checkout`.
+    pub detect_reentrant_checkout: Option<bool>,
+    /// See `Builder::thread_cache`.
+    pub thread_cache: Option<bool>,
+    #[serde(skip)]
+    _p: (),
+}
+
+impl fmt::Debug for PoolConfig {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("PoolConfig")
+            .field("max_size", &self.max_size)
+            .field("min_idle", &self.min_idle)
+            .field("test_on_check_out", &self.test_on_check_out)
+            .field("max_lifetime", &self.max_lifetime)
+            .field("max_uses", &self.max_uses)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("connection_timeout", &self.connection_timeout)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("validation_timeout", &self.validation_timeout)
+            .field("worker_threads", &self.worker_threads)
+            .field("synchronous", &self.synchronous)
+            .field("automatic_reaping", &self.automatic_reaping)
+            .field("idle_shards", &self.idle_shards)
+            .field("detect_reentrant_checkout", &self.detect_reentrant_checkout)
+            .field("thread_cache", &self.thread_cache)
+            .finish()
+    }
+}
+
+impl PoolConfig {
+    /// Creates a configuration from `R2D2_`-prefixed environment variables.
+    ///
+    /// Each field is read from the variable named after it in upper case, for
+    /// example `R2D2_MAX_SIZE` or `R2D2_IDLE_TIMEOUT`.
+    pub fn from_env() -> Result<PoolConfig, EnvError> {
+        PoolConfig::default().merge_env()
+    }
+
+    /// Overrides fields of this configuration with any that are set in
+    /// `R2D2_`-prefixed environment variables.
+    ///
+    /// This can be used to layer environment variables on top of a
+    /// configuration file.
+    pub fn merge_env(self) -> Result<PoolConfig, EnvError> {
+        self.merge_vars(|name| env::var(name).ok())
+    }
+
+    pub(crate) fn merge_vars<F>(mut self, var: F) -> Result<PoolConfig, EnvError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        parse_var(&var, "R2D2_MAX_SIZE", &mut self.max_size)?;
+        parse_var(&var, "R2D2_MIN_IDLE", &mut self.min_idle)?;
+        parse_var(&var, "R2D2_TEST_ON_CHECK_OUT", &mut self.test_on_check_out)?;
+        parse_duration_var(&var, "R2D2_MAX_LIFETIME", &mut self.max_lifetime)?;
+        parse_var(&var, "R2D2_MAX_USES", &mut self.max_uses)?;
+        parse_duration_var(&var, "R2D2_IDLE_TIMEOUT", &mut self.idle_timeout)?;
+        parse_duration_var(
+            &var,
+            "R2D2_CONNECTION_TIMEOUT",
+            &mut self.connection_timeout,
+        )?;
+        parse_duration_var(&var, "R2D2_CONNECT_TIMEOUT", &mut self.connect_timeout)?;
+        parse_duration_var(
+            &var,
+            "R2D2_VALIDATION_TIMEOUT",
+            &mut self.validation_timeout,
+        )?;
+        parse_var(&var, "R2D2_WORKER_THREADS", &mut self.worker_threads)?;
+        parse_var(&var, "R2D2_SYNCHRONOUS", &mut self.synchronous)?;
+        parse_var(&var, "R2D2_AUTOMATIC_REAPING", &mut self.automatic_reaping)?;
+        parse_var(&var, "R2D2_IDLE_SHARDS", &mut self.idle_shards)?;
+        parse_var(
+            &var,
+            "R2D2_DETECT_REENTRANT_CHECKOUT",
+            &mut self.detect_reentrant_checkout,
+        )?;
+        parse_var(&var, "R2D2_THREAD_CACHE", &mut self.thread_cache)?;
+        Ok(self)
+    }
+}
+
+fn parse_var<F, T>(var: &F, name: &str, field: &mut Option<T>) -> Result<(), EnvError>
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    if let Some(value) = var(name) {
+        match value.trim().parse() {
+            Ok(parsed) => *field = Some(parsed),
+            Err(e) => return Err(EnvError::new(name, value, e)),
+        }
+    }
+    Ok(())
+}
+
+fn parse_duration_var<F>(var: &F, name: &str, field: &mut Option<Duration>) -> Result<(), EnvError>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if let Some(value) = var(name) {
+        match humantime::parse_duration(value.trim()) {
+            Ok(parsed) => *field = Some(parsed),
+            Err(e) => return Err(EnvError::new(name, value, e)),
+        }
+    }
+    Ok(())
+}
+
+// Zero disables the optional timeouts and limits, which can't be zero.
+fn nonzero_duration(duration: Duration) -> Option<Duration> {
+    if duration == Duration::from_secs(0) {
+        None
+    } else {
+        Some(duration)
+    }
+}
+
+/// An error parsing a pool configuration from environment variables.
+#[derive(Debug)]
+pub struct EnvError {
+    var: String,
+    value: String,
+    msg: String,
+}
+
+impl EnvError {
+    fn new<E>(var: &str, value: String, e: E) -> EnvError
+    where
+        E: fmt::Display,
+    {
+        EnvError {
+            var: var.to_owned(),
+            value,
+            msg: e.to_string(),
+        }
+    }
+
+    /// Returns the name of the offending environment variable.
+    pub fn var(&self) -> &str {
+        &self.var
+    }
+}
+
+impl fmt::Display for EnvError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "invalid value `{}` for {}: {}",
+            self.value, self.var, self.msg
+        )
+    }
+}
+
+impl error::Error for EnvError {}
+
+impl<M> Builder<M>
+where
+    M: ManageConnection,
+{
+    /// Constructs a new `Builder` from a `PoolConfig`.
+    ///
+    /// Fields of the configuration which are not set are left at their
+    /// default values.
+    ///
+    /// Requires the `serde` Cargo feature.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any value would cause the corresponding `Builder` method to
+    /// panic, for example if `max_size` is 0.
+    pub fn from_config(config: &PoolConfig) -> Builder<M> {
+        let mut builder = Builder::new();
+
+        if let Some(max_size) = config.max_size {
+            builder = builder.max_size(max_size);
+        }
+        if let Some(min_idle) = config.min_idle {
+            builder = builder.min_idle(Some(min_idle));
+        }
+        if let Some(test_on_check_out) = config.test_on_check_out {
+            builder = builder.test_on_check_out(test_on_check_out);
+        }
+        if let Some(max_lifetime) = config.max_lifetime {
+            builder = builder.max_lifetime(nonzero_duration(max_lifetime));
+        }
+        if let Some(max_uses) = config.max_uses {
+            builder = builder.max_uses(Some(max_uses).filter(|&n| n > 0));
+        }
+        if let Some(idle_timeout) = config.idle_timeout {
+            builder = builder.idle_timeout(nonzero_duration(idle_timeout));
+        }
+        if let Some(connection_timeout) = config.connection_timeout {
+            builder = builder.connection_timeout(connection_timeout);
+        }
+        if let Some(connect_timeout) = config.connect_timeout {
+            builder = builder.connect_timeout(nonzero_duration(connect_timeout));
+        }
+        if let Some(validation_timeout) = config.validation_timeout {
+            builder = builder.validation_timeout(nonzero_duration(validation_timeout));
+        }
+        // a synchronous pool never uses its thread pool, so don't spawn one
+        let synchronous = config.synchronous.unwrap_or(false);
+        if let (Some(worker_threads), false) = (config.worker_threads, synchronous) {
+            #[allow(deprecated)]
+            let thread_pool =
+                ScheduledThreadPool::with_name("r2d2-worker-{}", worker_threads.get());
+            builder = builder.thread_pool(Arc::new(thread_pool));
+        }
+        if let Some(synchronous) = config.synchronous {
+            builder = builder.synchronous(synchronous);
+        }
+        if let Some(automatic_reaping) = config.automatic_reaping {
+            builder = builder.automatic_reaping(automatic_reaping);
+        }
+        if let Some(idle_shards) = config.idle_shards {
+            builder = builder.idle_shards(idle_shards);
+        }
+        if let Some(detect_reentrant_checkout) = config.detect_reentrant_checkout {
+            builder = builder.detect_reentrant_checkout(detect_reentrant_checkout);
+        }
+        if let Some(thread_cache) = config.thread_cache {
+            builder = builder.thread_cache(thread_cache);
+        }
+
+        builder
+    }
+}
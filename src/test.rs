@@ -1,12 +1,25 @@
 use parking_lot::Mutex;
+use scheduled_thread_pool::ScheduledThreadPool;
+use std::future::{self, Future};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
 use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, SyncSender};
-use std::sync::Arc;
+use std::sync::{Arc, Barrier};
+use std::task::{Context, Poll, Waker};
 use std::time::{Duration, Instant};
 use std::{error, fmt, mem, thread};
 
-use crate::event::{AcquireEvent, CheckinEvent, CheckoutEvent, ReleaseEvent, TimeoutEvent};
-use crate::{CustomizeConnection, HandleEvent, ManageConnection, Pool, PooledConnection};
+use crate::balance::{BalancedConnection, BalancingManager, Strategy};
+use crate::event::{
+    AcquireEvent, CheckinEvent, CheckoutEvent, ReleaseEvent, ReleaseReason, TimeoutEvent,
+};
+use crate::failover::{FailoverConnection, FailoverManager};
+use crate::keyed::KeyedPool;
+use crate::{
+    AsyncManageConnection, AsyncManager, BoxFuture, CustomizeConnection, HandleEvent,
+    ManageConnection, Pool, PooledConnection, WithError,
+};
 
 #[derive(Debug)]
 pub struct Error;
@@ -45,6 +58,37 @@ impl ManageConnection for OkManager {
     }
 }
 
+// Counts the connections it has opened.
+struct CountingManager(AtomicUsize);
+
+impl ManageConnection for CountingManager {
+    type Connection = FakeConnection;
+    type Error = Error;
+
+    fn connect(&self) -> Result<FakeConnection, Error> {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        Ok(FakeConnection(true))
+    }
+
+    fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn has_broken(&self, _: &mut FakeConnection) -> bool {
+        false
+    }
+}
+
+// Records the reason each connection was released for.
+#[derive(Debug)]
+struct ReleaseReasonHandler(Arc<Mutex<Vec<ReleaseReason>>>);
+
+impl HandleEvent for ReleaseReasonHandler {
+    fn handle_release(&self, event: ReleaseEvent) {
+        self.0.lock().push(event.reason());
+    }
+}
+
 struct NthConnectFailManager {
     n: Mutex<u32>,
 }
@@ -164,7 +208,11 @@ fn test_issue_2_unlocked_during_is_valid() {
         }
 
         fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
-            if self.first.compare_and_swap(true, false, Ordering::SeqCst) {
+            if self
+                .first
+                .compare_exchange(true, false, Ordering::SeqCst, Ordering::SeqCst)
+                .is_ok()
+            {
                 self.s.lock().send(()).unwrap();
                 self.r.lock().recv().unwrap();
             }
@@ -346,6 +394,67 @@ fn test_connection_customizer() {
     assert!(RELEASED.load(Ordering::SeqCst));
 }
 
+#[test]
+fn checkin_customizer() {
+    #[derive(Debug)]
+    struct Customizer;
+
+    impl CustomizeConnection<FakeConnection, Error> for Customizer {
+        fn on_checkin(&self, conn: &mut FakeConnection) -> Result<(), Error> {
+            if conn.0 {
+                Ok(())
+            } else {
+                Err(Error)
+            }
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(1)
+        .synchronous(true)
+        .connection_customizer(Box::new(Customizer))
+        .build(OkManager)
+        .unwrap();
+
+    drop(pool.get().unwrap());
+    assert_eq!(1, pool.state().idle_connections);
+
+    let mut conn = pool.get().unwrap();
+    conn.0 = false;
+    drop(conn);
+    assert_eq!(0, pool.state().connections);
+    assert_eq!(0, pool.state().idle_connections);
+}
+
+#[test]
+fn checkout_customizer() {
+    #[derive(Debug)]
+    struct Customizer(AtomicUsize);
+
+    impl CustomizeConnection<FakeConnection, Error> for Customizer {
+        fn on_checkout(&self, conn: &mut FakeConnection) -> Result<(), Error> {
+            if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(Error)
+            } else {
+                conn.0 = false;
+                Ok(())
+            }
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(2)
+        .synchronous(true)
+        .connection_customizer(Box::new(Customizer(AtomicUsize::new(0))))
+        .build(OkManager)
+        .unwrap();
+
+    // the first connection is discarded and the second one is used
+    let conn = pool.get().unwrap();
+    assert!(!conn.0);
+    assert_eq!(1, pool.state().connections);
+}
+
 #[test]
 fn test_idle_timeout() {
     static DROPPED: AtomicUsize = AtomicUsize::new(0);
@@ -383,17 +492,42 @@ fn test_idle_timeout() {
 
     let pool = Pool::builder()
         .max_size(5)
-        .idle_timeout(Some(Duration::from_secs(1)))
-        .reaper_rate(Duration::from_secs(1))
+        .min_idle(Some(0))
+        .idle_timeout(Some(Duration::from_millis(100)))
+        .automatic_reaping(false)
         .build(Handler(AtomicIsize::new(5)))
         .unwrap();
-    let conn = pool.get().unwrap();
-    thread::sleep(Duration::from_secs(2));
+    let mut conns = (0..5).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    let conn = conns.pop().unwrap();
+    drop(conns);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(4, pool.run_maintenance().reaped_connections);
     assert_eq!(4, DROPPED.load(Ordering::SeqCst));
     drop(conn);
     assert_eq!(4, DROPPED.load(Ordering::SeqCst));
 }
 
+#[test]
+fn idle_timeout_keeps_min_idle() {
+    let pool = Pool::builder()
+        .max_size(5)
+        .min_idle(Some(2))
+        .idle_timeout(Some(Duration::from_millis(100)))
+        .automatic_reaping(false)
+        .build(CountingManager(AtomicUsize::new(0)))
+        .unwrap();
+    let conns = (0..5).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    drop(conns);
+    assert_eq!(5, pool.state().idle_connections);
+
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(3, pool.run_maintenance().reaped_connections);
+    assert_eq!(2, pool.state().idle_connections);
+    assert_eq!(2, pool.state().connections);
+    // the remaining connections are kept rather than replaced
+    assert_eq!(5, pool.0.manager.0.load(Ordering::SeqCst));
+}
+
 #[test]
 fn idle_timeout_partial_use() {
     static DROPPED: AtomicUsize = AtomicUsize::new(0);
@@ -431,14 +565,19 @@ fn idle_timeout_partial_use() {
 
     let pool = Pool::builder()
         .max_size(5)
-        .idle_timeout(Some(Duration::from_secs(1)))
-        .reaper_rate(Duration::from_secs(1))
+        .idle_timeout(Some(Duration::from_millis(200)))
+        .automatic_reaping(false)
+        .min_idle(Some(0))
         .build(Handler(AtomicIsize::new(5)))
         .unwrap();
+    let conns = (0..5).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    drop(conns);
+    // the same connection keeps being reused, so only the others expire
     for _ in 0..8 {
-        thread::sleep(Duration::from_millis(250));
+        thread::sleep(Duration::from_millis(50));
         pool.get().unwrap();
     }
+    assert_eq!(4, pool.run_maintenance().reaped_connections);
     assert_eq!(4, DROPPED.load(Ordering::SeqCst));
     assert_eq!(1, pool.state().connections);
 }
@@ -494,6 +633,34 @@ fn test_max_lifetime() {
     assert!(pool.get().is_err());
 }
 
+#[test]
+fn max_uses() {
+    let reasons = Arc::new(Mutex::new(vec![]));
+    let pool = Pool::builder()
+        .max_size(1)
+        .min_idle(Some(0))
+        .max_uses(Some(2))
+        .synchronous(true)
+        .event_handler(Box::new(ReleaseReasonHandler(reasons.clone())))
+        .build(OkManager)
+        .unwrap();
+
+    let conn = pool.get().unwrap();
+    let id = PooledConnection::id(&conn);
+    drop(conn);
+    assert_eq!(1, pool.state().idle_connections);
+
+    let conn = pool.get().unwrap();
+    assert_eq!(id, PooledConnection::id(&conn));
+    assert_eq!(2, PooledConnection::use_count(&conn));
+    drop(conn);
+    assert_eq!(0, pool.state().connections);
+    assert_eq!(vec![ReleaseReason::MaxUses], *reasons.lock());
+
+    let conn = pool.get().unwrap();
+    assert_ne!(id, PooledConnection::id(&conn));
+}
+
 #[test]
 fn min_idle() {
     struct Connection;
@@ -534,6 +701,23 @@ fn min_idle() {
     assert_eq!(5, pool.state().connections);
 }
 
+#[test]
+fn min_idle_max_lifetime() {
+    let pool = Pool::builder()
+        .max_size(5)
+        .min_idle(Some(2))
+        .max_lifetime(Some(Duration::from_secs(1)))
+        .reaper_rate(Duration::from_millis(250))
+        .build(CountingManager(AtomicUsize::new(0)))
+        .unwrap();
+    assert_eq!(2, pool.0.manager.0.load(Ordering::SeqCst));
+
+    // expired connections are closed even though that drops below min_idle
+    thread::sleep(Duration::from_millis(1500));
+    assert!(pool.0.manager.0.load(Ordering::SeqCst) >= 4);
+    assert_eq!(2, pool.state().idle_connections);
+}
+
 #[test]
 fn conns_drop_on_pool_drop() {
     static DROPPED: AtomicUsize = AtomicUsize::new(0);
@@ -722,3 +906,1447 @@ fn extensions() {
     let conn = pool.get().unwrap();
     assert_eq!(PooledConnection::extensions(&conn).get::<i32>(), Some(&1));
 }
+
+#[test]
+fn connection_metadata() {
+    let pool = Pool::builder()
+        .max_size(1)
+        .synchronous(true)
+        .build(OkManager)
+        .unwrap();
+
+    let conn = pool.get().unwrap();
+    let id = PooledConnection::id(&conn);
+    assert_eq!(1, PooledConnection::use_count(&conn));
+    drop(conn);
+
+    thread::sleep(Duration::from_millis(50));
+    let before = Instant::now();
+    let conn = pool.get().unwrap();
+    assert_eq!(id, PooledConnection::id(&conn));
+    assert_eq!(2, PooledConnection::use_count(&conn));
+    assert!(PooledConnection::idle_duration_before_checkout(&conn) >= Duration::from_millis(50));
+    assert!(PooledConnection::age(&conn) >= Duration::from_millis(50));
+    assert!(PooledConnection::checked_out_at(&conn) >= before);
+}
+
+#[test]
+fn affinity() {
+    let pool = Pool::builder().max_size(3).build(OkManager).unwrap();
+
+    let mut conn1 = pool.get().unwrap();
+    let mut conn2 = pool.get().unwrap();
+    let conn3 = pool.get().unwrap();
+
+    PooledConnection::extensions_mut(&mut conn1).insert("a");
+    PooledConnection::extensions_mut(&mut conn2).insert("b");
+
+    drop(conn1);
+    drop(conn2);
+    drop(conn3);
+
+    let conn = pool
+        .get_with_affinity(|ext| ext.get::<&str>() == Some(&"a"))
+        .unwrap();
+    assert_eq!(
+        PooledConnection::extensions(&conn).get::<&str>(),
+        Some(&"a")
+    );
+
+    // falls back to any idle connection
+    let conn2 = pool
+        .get_with_affinity(|ext| ext.get::<&str>() == Some(&"c"))
+        .unwrap();
+    assert_eq!(PooledConnection::extensions(&conn2).get::<&str>(), None);
+    let conn3 = pool
+        .get_with_affinity(|ext| ext.get::<&str>() == Some(&"c"))
+        .unwrap();
+    assert_eq!(
+        PooledConnection::extensions(&conn3).get::<&str>(),
+        Some(&"b")
+    );
+}
+
+#[test]
+fn synchronous_connects_on_caller() {
+    struct Handler(Mutex<Vec<thread::ThreadId>>);
+
+    impl ManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> Result<FakeConnection, Error> {
+            self.0.lock().push(thread::current().id());
+            Ok(FakeConnection(true))
+        }
+
+        fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(3)
+        .min_idle(Some(1))
+        .synchronous(true)
+        .build(Handler(Mutex::new(vec![])))
+        .unwrap();
+    assert_eq!(1, pool.state().connections);
+
+    let conns = (0..3).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    assert_eq!(3, pool.state().connections);
+    assert!(pool.get_timeout(Duration::from_millis(100)).is_err());
+    drop(conns);
+    assert_eq!(3, pool.state().idle_connections);
+
+    let threads = pool.0.manager.0.lock();
+    assert_eq!(3, threads.len());
+    assert!(threads.iter().all(|id| *id == thread::current().id()));
+}
+
+#[test]
+fn synchronous_initialization_failure() {
+    let manager = NthConnectFailManager { n: Mutex::new(0) };
+    let err = Pool::builder()
+        .connection_timeout(Duration::from_secs(1))
+        .synchronous(true)
+        .build(manager)
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("blammo"));
+
+    let manager = NthConnectFailManager { n: Mutex::new(0) };
+    let pool = Pool::builder()
+        .connection_timeout(Duration::from_secs(1))
+        .synchronous(true)
+        .build_unchecked(manager);
+    let err = pool.get().err().unwrap();
+    assert!(err.to_string().contains("blammo"));
+}
+
+#[test]
+fn synchronous_reaping() {
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+    struct Connection;
+
+    impl Drop for Connection {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    struct Handler;
+
+    impl ManageConnection for Handler {
+        type Connection = Connection;
+        type Error = Error;
+
+        fn connect(&self) -> Result<Connection, Error> {
+            Ok(Connection)
+        }
+
+        fn is_valid(&self, _: &mut Connection) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut Connection) -> bool {
+            false
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(5)
+        .min_idle(Some(1))
+        .idle_timeout(Some(Duration::from_secs(1)))
+        .reaper_rate(Duration::from_millis(500))
+        .synchronous(true)
+        .build(Handler)
+        .unwrap();
+    let mut conns = (0..5).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    let conn = conns.pop().unwrap();
+    drop(conns);
+    thread::sleep(Duration::from_secs(2));
+    // nothing runs in the background
+    assert_eq!(0, DROPPED.load(Ordering::SeqCst));
+    drop(conn);
+    assert_eq!(4, DROPPED.load(Ordering::SeqCst));
+    assert_eq!(1, pool.state().connections);
+}
+
+#[test]
+fn manual_maintenance() {
+    struct Handler(AtomicBool);
+
+    impl ManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> Result<FakeConnection, Error> {
+            Ok(FakeConnection(true))
+        }
+
+        fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
+            if self.0.load(Ordering::SeqCst) {
+                Ok(())
+            } else {
+                Err(Error)
+            }
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(3)
+        .min_idle(Some(1))
+        .idle_timeout(Some(Duration::from_secs(1)))
+        .reaper_rate(Duration::from_millis(100))
+        .automatic_reaping(false)
+        .build(Handler(AtomicBool::new(true)))
+        .unwrap();
+
+    let conns = (0..3).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    drop(conns);
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(3, pool.state().idle_connections);
+
+    let report = pool.run_maintenance();
+    assert_eq!(2, report.reaped_connections);
+    assert_eq!(0, report.invalid_connections);
+    assert_eq!(0, report.new_connections);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(1, pool.state().idle_connections);
+
+    pool.0.manager.0.store(false, Ordering::SeqCst);
+    let report = pool.run_maintenance();
+    assert_eq!(0, report.reaped_connections);
+    assert_eq!(1, report.invalid_connections);
+    assert_eq!(1, report.new_connections);
+}
+
+#[test]
+fn synchronous_maintenance() {
+    let pool = Pool::builder()
+        .max_size(3)
+        .min_idle(Some(1))
+        .synchronous(true)
+        .build(OkManager)
+        .unwrap();
+
+    let _conns = (0..2).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    assert_eq!(2, pool.state().connections);
+
+    let report = pool.run_maintenance();
+    assert_eq!(1, report.new_connections);
+    assert_eq!(3, pool.state().connections);
+    assert_eq!(1, pool.state().idle_connections);
+}
+
+#[test]
+fn idle_shards() {
+    let pool = Pool::builder()
+        .max_size(4)
+        .idle_shards(4)
+        .connection_timeout(Duration::from_secs(5))
+        .build(OkManager)
+        .unwrap();
+
+    // connections returned on one thread are available to others
+    let conns = (0..4).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    drop(conns);
+    let pool2 = pool.clone();
+    thread::spawn(move || {
+        let conns = (0..4).map(|_| pool2.try_get().unwrap()).collect::<Vec<_>>();
+        drop(conns);
+    })
+    .join()
+    .unwrap();
+
+    let threads = (0..8)
+        .map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for _ in 0..1000 {
+                    pool.get().unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(4, pool.state().connections);
+    assert_eq!(4, pool.state().idle_connections);
+}
+
+#[test]
+fn balancing_round_robin() {
+    let manager = BalancingManager::new(vec![OkManager, OkManager], Strategy::RoundRobin);
+    let pool = Pool::builder().max_size(4).build(manager).unwrap();
+
+    let states = pool.endpoint_states();
+    assert_eq!(2, states.len());
+    for state in &states {
+        assert_eq!(2, state.connections);
+        assert_eq!(2, state.idle_connections);
+    }
+
+    let conn = pool.get().unwrap();
+    let endpoint = BalancedConnection::endpoint(&conn);
+    assert_eq!(1, pool.endpoint_states()[endpoint].idle_connections);
+    assert_eq!(2, pool.endpoint_states()[endpoint].connections);
+}
+
+#[test]
+fn balancing_weighted() {
+    let manager = BalancingManager::new(vec![OkManager, OkManager], Strategy::Weighted(vec![3, 1]));
+    let pool = Pool::builder().max_size(8).build(manager).unwrap();
+
+    let states = pool.endpoint_states();
+    assert_eq!(6, states[0].connections);
+    assert_eq!(2, states[1].connections);
+}
+
+#[test]
+fn balancing_least_connections() {
+    let manager = BalancingManager::new(vec![OkManager, OkManager], Strategy::LeastConnections);
+    let pool = Pool::builder()
+        .max_size(4)
+        .min_idle(Some(0))
+        .synchronous(true)
+        .build(manager)
+        .unwrap();
+
+    let conns = (0..4).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    drop(conns);
+    let states = pool.endpoint_states();
+    assert_eq!(2, states[0].connections);
+    assert_eq!(2, states[1].connections);
+}
+
+#[test]
+fn balancing_skips_unhealthy_endpoints() {
+    let manager = BalancingManager::new(
+        vec![
+            NthConnectFailManager { n: Mutex::new(0) },
+            NthConnectFailManager { n: Mutex::new(10) },
+        ],
+        Strategy::RoundRobin,
+    )
+    .failure_threshold(1);
+    let pool = Pool::builder()
+        .max_size(4)
+        .synchronous(true)
+        .build(manager)
+        .unwrap();
+
+    let states = pool.endpoint_states();
+    assert_eq!(0, states[0].connections);
+    assert_eq!(4, states[1].connections);
+    assert!(!pool.0.manager.is_healthy(0));
+    assert!(pool.0.manager.is_healthy(1));
+    assert_eq!(1, pool.0.manager.consecutive_failures(0));
+}
+
+// Connects and validates connections only while enabled.
+struct ToggleManager(AtomicBool);
+
+impl ManageConnection for ToggleManager {
+    type Connection = FakeConnection;
+    type Error = Error;
+
+    fn connect(&self) -> Result<FakeConnection, Error> {
+        if self.0.load(Ordering::SeqCst) {
+            Ok(FakeConnection(true))
+        } else {
+            Err(Error)
+        }
+    }
+
+    fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
+        if self.0.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(Error)
+        }
+    }
+
+    fn has_broken(&self, _: &mut FakeConnection) -> bool {
+        false
+    }
+}
+
+#[test]
+fn failover() {
+    let manager = FailoverManager::new(vec![
+        ToggleManager(AtomicBool::new(true)),
+        ToggleManager(AtomicBool::new(true)),
+    ]);
+    let pool = Pool::builder()
+        .max_size(2)
+        .automatic_reaping(false)
+        .build(manager)
+        .unwrap();
+
+    let conn = pool.get().unwrap();
+    assert_eq!(0, FailoverConnection::endpoint(&conn));
+
+    pool.0.manager.manager(0).0.store(false, Ordering::SeqCst);
+    let conn2 = pool.get().unwrap();
+    assert_eq!(1, FailoverConnection::endpoint(&conn2));
+    assert_eq!(1, pool.0.manager.active_endpoint());
+
+    // connections to the old endpoint are closed when returned
+    drop(conn);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(2, pool.state().connections);
+    assert_eq!(1, pool.state().idle_connections);
+
+    // and the pool sticks with the new endpoint once the old one recovers
+    pool.0.manager.manager(0).0.store(true, Ordering::SeqCst);
+    let conn = pool.get().unwrap();
+    assert_eq!(1, FailoverConnection::endpoint(&conn));
+}
+
+#[test]
+fn failover_evicts_idle_connections() {
+    let manager = FailoverManager::new(vec![
+        ToggleManager(AtomicBool::new(true)),
+        ToggleManager(AtomicBool::new(true)),
+    ]);
+    let pool = Pool::builder()
+        .max_size(3)
+        .min_idle(Some(0))
+        .test_on_check_out(false)
+        .synchronous(true)
+        .build(manager)
+        .unwrap();
+
+    let conn = pool.get().unwrap();
+    drop(pool.get().unwrap());
+    assert_eq!(1, pool.state().idle_connections);
+
+    // another thread fails over while both connections are still around
+    pool.0.manager.manager(0).0.store(false, Ordering::SeqCst);
+    let standby = pool.0.manager.connect().unwrap();
+    assert_eq!(1, FailoverConnection::endpoint(&standby));
+
+    // the idle connection is closed right away, and the other on checkin
+    assert_eq!(0, pool.state().idle_connections);
+    assert_eq!(1, pool.state().connections);
+    drop(conn);
+    assert_eq!(0, pool.state().connections);
+
+    let conn = pool.get().unwrap();
+    assert_eq!(1, FailoverConnection::endpoint(&conn));
+}
+
+struct KeyManager(u32);
+
+impl ManageConnection for KeyManager {
+    type Connection = u32;
+    type Error = Error;
+
+    fn connect(&self) -> Result<u32, Error> {
+        Ok(self.0)
+    }
+
+    fn is_valid(&self, _: &mut u32) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn has_broken(&self, _: &mut u32) -> bool {
+        false
+    }
+}
+
+#[test]
+fn keyed_pool() {
+    let pool = KeyedPool::builder()
+        .max_connections(3)
+        .key_config(|key: &u32, builder| builder.max_size(if *key == 1 { 1 } else { 2 }))
+        .build(|key: &u32| KeyManager(*key));
+
+    let conn1 = pool.get(&1).unwrap();
+    assert_eq!(1, *conn1);
+    // the per-key limit applies
+    assert!(pool.get_timeout(&1, Duration::from_millis(100)).is_err());
+
+    let conn2a = pool.get(&2).unwrap();
+    let conn2b = pool.get(&2).unwrap();
+    assert_eq!(2, *conn2a);
+    assert_eq!(2, *conn2b);
+    assert_eq!(3, pool.state().connections);
+
+    // and so does the global one
+    assert!(pool.get_timeout(&3, Duration::from_millis(100)).is_err());
+
+    // an idle connection of another key is closed to make room
+    drop(conn1);
+    let conn3 = pool.get(&3).unwrap();
+    assert_eq!(3, *conn3);
+    assert_eq!(0, pool.pool(&1).state().connections);
+    assert_eq!(3, pool.state().connections);
+}
+
+#[test]
+fn keyed_pool_reclaims_later_idle_connection() {
+    let pool = KeyedPool::builder()
+        .max_connections(1)
+        .build(|key: &u32| KeyManager(*key));
+
+    let conn1 = pool.get(&1).unwrap();
+
+    // the other key is refused before the connection goes idle
+    let pool2 = pool.clone();
+    let handle = thread::spawn(move || pool2.get_timeout(&2, Duration::from_secs(3)));
+    thread::sleep(Duration::from_millis(500));
+    drop(conn1);
+
+    let conn2 = handle.join().unwrap().unwrap();
+    assert_eq!(2, *conn2);
+    assert_eq!(0, pool.pool(&1).state().connections);
+}
+
+#[test]
+fn keyed_pool_evicts_idle_keys() {
+    let pool = KeyedPool::builder()
+        .idle_key_timeout(Some(Duration::from_millis(200)))
+        .reaper_rate(Duration::from_millis(100))
+        .build(|key: &u32| KeyManager(*key));
+
+    drop(pool.get(&1).unwrap());
+    let _conn = pool.get(&2).unwrap();
+    let mut keys = pool.keys();
+    keys.sort();
+    assert_eq!(vec![1, 2], keys);
+
+    thread::sleep(Duration::from_millis(600));
+    // keys with connections checked out are kept
+    assert_eq!(vec![2], pool.keys());
+    assert_eq!(1, pool.state().connections);
+}
+
+#[test]
+fn async_manager() {
+    #[derive(Default)]
+    struct Gate {
+        open: bool,
+        waker: Option<Waker>,
+    }
+
+    struct Connect(Arc<Mutex<Gate>>);
+
+    impl Future for Connect {
+        type Output = Result<FakeConnection, Error>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+            let mut gate = self.0.lock();
+            if gate.open {
+                Poll::Ready(Ok(FakeConnection(true)))
+            } else {
+                gate.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    struct Handler(Arc<Mutex<Gate>>);
+
+    impl AsyncManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> BoxFuture<'static, Result<FakeConnection, Error>> {
+            Box::pin(Connect(self.0.clone()))
+        }
+
+        fn is_valid<'a>(&'a self, _: &'a mut FakeConnection) -> BoxFuture<'a, Result<(), Error>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    let gate = Arc::new(Mutex::new(Gate::default()));
+    let thread_pool = Arc::new(ScheduledThreadPool::new(1));
+    let pool = Pool::builder()
+        .max_size(1)
+        .thread_pool(thread_pool.clone())
+        .build_unchecked(AsyncManager::new(Handler(gate.clone())));
+
+    // the only worker isn't stuck waiting on the connection
+    let (tx, rx) = mpsc::channel();
+    thread_pool.execute(move || tx.send(()).unwrap());
+    rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert!(pool.try_get().is_none());
+
+    let waker = {
+        let mut gate = gate.lock();
+        gate.open = true;
+        gate.waker.take().unwrap()
+    };
+    waker.wake();
+
+    let conn = pool.get_timeout(Duration::from_secs(1)).unwrap();
+    assert!(conn.0);
+}
+
+#[test]
+fn connect_timeout() {
+    struct Handler(AtomicUsize);
+
+    impl ManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> Result<FakeConnection, Error> {
+            unreachable!()
+        }
+
+        fn connect_with_deadline(&self, deadline: Instant) -> Result<FakeConnection, Error> {
+            assert!(deadline <= Instant::now() + Duration::from_millis(100));
+            // the first attempt hangs well past its deadline
+            if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                thread::sleep(Duration::from_secs(2));
+            }
+            Ok(FakeConnection(true))
+        }
+
+        fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(1)
+        .connect_timeout(Some(Duration::from_millis(100)))
+        .build_unchecked(Handler(AtomicUsize::new(0)));
+
+    let start = Instant::now();
+    let conn = pool.get_timeout(Duration::from_millis(1500)).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    drop(conn);
+
+    // the late connection is closed rather than added to the pool
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(1, pool.state().connections);
+    assert_eq!(2, pool.0.manager.0.load(Ordering::SeqCst));
+}
+
+#[test]
+fn connect_timeout_busy_workers() {
+    struct Handler;
+
+    impl ManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> Result<FakeConnection, Error> {
+            thread::sleep(Duration::from_secs(2));
+            Ok(FakeConnection(true))
+        }
+
+        fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    // the only worker is stuck in the hung connect, but it still times out
+    let pool = Pool::builder()
+        .max_size(1)
+        .thread_pool(Arc::new(ScheduledThreadPool::new(1)))
+        .connect_timeout(Some(Duration::from_millis(100)))
+        .build_unchecked(Handler);
+
+    thread::sleep(Duration::from_millis(500));
+    let last_error = pool.health().last_error.unwrap();
+    assert!(last_error.contains("timed out"));
+}
+
+#[test]
+fn async_connect_timeout() {
+    struct Handler;
+
+    impl AsyncManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> BoxFuture<'static, Result<FakeConnection, Error>> {
+            Box::pin(future::pending())
+        }
+
+        fn is_valid<'a>(&'a self, _: &'a mut FakeConnection) -> BoxFuture<'a, Result<(), Error>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(1)
+        .synchronous(true)
+        .connect_timeout(Some(Duration::from_millis(100)))
+        .build_unchecked(AsyncManager::new(Handler));
+
+    // the hung connect is given up on rather than blocking the caller
+    let start = Instant::now();
+    assert!(pool.get_timeout(Duration::from_millis(500)).is_err());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert!(pool.health().last_error.unwrap().contains("timed out"));
+}
+
+#[test]
+fn async_validation_timeout() {
+    struct Handler;
+
+    impl AsyncManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> BoxFuture<'static, Result<FakeConnection, Error>> {
+            Box::pin(async { Ok(FakeConnection(true)) })
+        }
+
+        fn is_valid<'a>(&'a self, _: &'a mut FakeConnection) -> BoxFuture<'a, Result<(), Error>> {
+            Box::pin(future::pending())
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(1)
+        .synchronous(true)
+        .validation_timeout(Some(Duration::from_millis(100)))
+        .build(AsyncManager::new(Handler))
+        .unwrap();
+
+    // the hung check fails the idle connection, so a new one is opened
+    let id = PooledConnection::id(&pool.get().unwrap());
+    let conn = pool.get_timeout(Duration::from_secs(1)).unwrap();
+    assert_ne!(id, PooledConnection::id(&conn));
+    assert!(pool.health().last_error.unwrap().contains("timed out"));
+}
+
+#[test]
+fn wrapped_async_manager() {
+    struct Handler(AtomicBool);
+
+    impl AsyncManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> BoxFuture<'static, Result<FakeConnection, Error>> {
+            if self.0.load(Ordering::SeqCst) {
+                Box::pin(async { Ok(FakeConnection(true)) })
+            } else {
+                Box::pin(future::pending())
+            }
+        }
+
+        fn is_valid<'a>(&'a self, _: &'a mut FakeConnection) -> BoxFuture<'a, Result<(), Error>> {
+            Box::pin(future::pending())
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    fn check<M>(manager: impl Fn() -> M, connects: impl Fn(&M) -> &AtomicBool)
+    where
+        M: ManageConnection,
+    {
+        // background connects don't tie up the only worker
+        let thread_pool = Arc::new(ScheduledThreadPool::new(1));
+        let pool = Pool::builder()
+            .max_size(1)
+            .thread_pool(thread_pool.clone())
+            .build_unchecked(manager());
+        let (tx, rx) = mpsc::channel();
+        thread_pool.execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert!(pool.try_get().is_none());
+
+        // connects and validity checks on the caller give up at the deadline
+        let pool = Pool::builder()
+            .max_size(1)
+            .synchronous(true)
+            .connect_timeout(Some(Duration::from_millis(100)))
+            .validation_timeout(Some(Duration::from_millis(100)))
+            .build_unchecked(manager());
+        let start = Instant::now();
+        assert!(pool.get_timeout(Duration::from_millis(500)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(pool.health().last_error.unwrap().contains("timed out"));
+
+        connects(&pool.0.manager).store(true, Ordering::SeqCst);
+        let id = PooledConnection::id(&pool.get().unwrap());
+        let conn = pool.get_timeout(Duration::from_secs(1)).unwrap();
+        assert_ne!(id, PooledConnection::id(&conn));
+    }
+
+    check(
+        || {
+            BalancingManager::new(
+                vec![AsyncManager::new(Handler(AtomicBool::new(false)))],
+                Strategy::RoundRobin,
+            )
+        },
+        |m| &m.manager(0).get_ref().0,
+    );
+    check(
+        || FailoverManager::new(vec![AsyncManager::new(Handler(AtomicBool::new(false)))]),
+        |m| &m.manager(0).get_ref().0,
+    );
+}
+
+#[test]
+fn validation_timeout() {
+    struct Handler {
+        connects: AtomicUsize,
+        slow: Mutex<Option<Duration>>,
+    }
+
+    impl ManageConnection for Handler {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> Result<FakeConnection, Error> {
+            self.connects.fetch_add(1, Ordering::SeqCst);
+            Ok(FakeConnection(true))
+        }
+
+        fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
+            if let Some(delay) = self.slow.lock().take() {
+                thread::sleep(delay);
+            }
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(1)
+        .validation_timeout(Some(Duration::from_millis(100)))
+        .build(Handler {
+            connects: AtomicUsize::new(0),
+            slow: Mutex::new(None),
+        })
+        .unwrap();
+
+    // a slow check discards the connection, and a new one is used instead
+    *pool.0.manager.slow.lock() = Some(Duration::from_millis(300));
+    let start = Instant::now();
+    drop(pool.get_timeout(Duration::from_secs(2)).unwrap());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(2, pool.0.manager.connects.load(Ordering::SeqCst));
+
+    // the time spent validating counts against the caller's timeout
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(Handler {
+            connects: AtomicUsize::new(0),
+            slow: Mutex::new(None),
+        })
+        .unwrap();
+    *pool.0.manager.slow.lock() = Some(Duration::from_millis(500));
+    let start = Instant::now();
+    assert!(pool.get_timeout(Duration::from_millis(200)).is_err());
+    assert!(start.elapsed() < Duration::from_secs(1));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn pool_config() {
+    use crate::{Builder, PoolConfig};
+    use std::collections::HashMap;
+
+    let config: PoolConfig = serde_json::from_str(
+        r#"{
+            "max_size": 4,
+            "min_idle": 1,
+            "idle_timeout": "90s",
+            "max_lifetime": "0s",
+            "connection_timeout": "1m 30s",
+            "worker_threads": 2,
+            "synchronous": true
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(Some(Duration::from_secs(90)), config.idle_timeout);
+    assert!(serde_json::from_str::<PoolConfig>(r#"{"max_sise": 4}"#).is_err());
+
+    let vars = vec![
+        ("R2D2_MAX_SIZE", "6"),
+        ("R2D2_VALIDATION_TIMEOUT", "250ms"),
+        ("R2D2_AUTOMATIC_REAPING", "false"),
+        ("R2D2_DETECT_REENTRANT_CHECKOUT", "true"),
+        ("R2D2_THREAD_CACHE", "true"),
+    ]
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+    let config = config
+        .merge_vars(|name| vars.get(name).map(|v| v.to_string()))
+        .unwrap();
+
+    let pool = Builder::from_config(&config).build(OkManager).unwrap();
+    assert_eq!(6, pool.max_size());
+    assert_eq!(Some(1), pool.min_idle());
+    assert_eq!(Some(Duration::from_secs(90)), pool.idle_timeout());
+    assert_eq!(None, pool.max_lifetime());
+    assert_eq!(Duration::from_secs(90), pool.connection_timeout());
+    assert_eq!(Some(Duration::from_millis(250)), pool.validation_timeout());
+    assert_eq!(1, pool.state().connections);
+    assert!(!pool.0.config.automatic_reaping);
+    assert!(pool.0.config.detect_reentrant_checkout);
+    assert!(pool.0.config.thread_cache);
+
+    let err = PoolConfig::default()
+        .merge_vars(|_| Some("soon".to_owned()))
+        .unwrap_err();
+    assert_eq!("R2D2_MAX_SIZE", err.var());
+
+    // a thread pool can't be empty
+    assert!(serde_json::from_str::<PoolConfig>(r#"{"worker_threads": 0}"#).is_err());
+    let err = PoolConfig::default()
+        .merge_vars(|name| Some("0".to_owned()).filter(|_| name == "R2D2_WORKER_THREADS"))
+        .unwrap_err();
+    assert_eq!("R2D2_WORKER_THREADS", err.var());
+}
+
+#[test]
+fn reconfigure() {
+    let pool = Pool::builder()
+        .max_size(3)
+        .min_idle(Some(0))
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .reaper_rate(Duration::from_millis(100))
+        .build(OkManager)
+        .unwrap();
+    assert!(pool.0.reaper.lock().is_none());
+
+    let conns = (0..3).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    drop(conns);
+    assert_eq!(3, pool.state().idle_connections);
+
+    pool.reconfigure(|cfg| {
+        cfg.idle_timeout = Some(Duration::from_millis(200));
+        cfg.connection_timeout = Duration::from_secs(1);
+        cfg.test_on_check_out = false;
+    });
+    assert_eq!(Some(Duration::from_millis(200)), pool.idle_timeout());
+    assert_eq!(Duration::from_secs(1), pool.connection_timeout());
+    assert!(!pool.test_on_check_out());
+    assert!(pool.0.reaper.lock().is_some());
+
+    thread::sleep(Duration::from_millis(600));
+    assert_eq!(0, pool.state().connections);
+
+    pool.reconfigure(|cfg| cfg.idle_timeout = None);
+    assert!(pool.0.reaper.lock().is_none());
+
+    // the pool can still be used while the closure runs
+    pool.reconfigure(|cfg| {
+        drop(pool.get().unwrap());
+        cfg.connection_timeout = pool.connection_timeout() * 2;
+    });
+    assert_eq!(Duration::from_secs(2), pool.connection_timeout());
+}
+
+#[test]
+fn health() {
+    let pool = Pool::builder()
+        .max_size(2)
+        .min_idle(Some(1))
+        .synchronous(true)
+        .build(NthConnectFailManager { n: Mutex::new(1) })
+        .unwrap();
+
+    let health = pool.health();
+    assert_eq!(None, health.last_error);
+    assert_eq!(0, health.consecutive_connect_failures);
+    assert!(health.since_last_connect.is_some());
+    assert!(health.has_live_connection);
+    assert!(health.min_idle_satisfied);
+
+    let conn = pool.get().unwrap();
+    assert!(!pool.health().min_idle_satisfied);
+
+    assert!(pool.get_timeout(Duration::from_millis(100)).is_err());
+    let health = pool.health();
+    assert_eq!(Some("blammo"), health.last_error.as_deref());
+    assert!(health.last_error_at.is_some());
+    assert!(health.consecutive_connect_failures > 0);
+    assert!(health.has_live_connection);
+
+    drop(conn);
+    assert!(pool.health().min_idle_satisfied);
+}
+
+#[test]
+fn retain() {
+    let pool = Pool::builder()
+        .max_size(3)
+        .min_idle(Some(0))
+        .synchronous(true)
+        .build(OkManager)
+        .unwrap();
+
+    let mut conns = (0..3).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    for (i, conn) in conns.iter_mut().enumerate() {
+        PooledConnection::extensions_mut(conn).insert(i);
+    }
+    let conn = conns.pop().unwrap();
+    drop(conns);
+    assert_eq!(2, pool.state().idle_connections);
+
+    pool.retain(|_, extensions| extensions.get::<usize>() == Some(&0));
+    assert_eq!(1, pool.state().idle_connections);
+    assert_eq!(2, pool.state().connections);
+
+    // the checked out connection is checked when it's returned
+    drop(conn);
+    assert_eq!(1, pool.state().connections);
+
+    // connections established afterwards are not
+    let conns = (0..3).map(|_| pool.get().unwrap()).collect::<Vec<_>>();
+    drop(conns);
+    assert_eq!(3, pool.state().connections);
+}
+
+#[test]
+fn invalidate_all() {
+    let pool = Pool::builder()
+        .max_size(3)
+        .min_idle(Some(2))
+        .build(CountingManager(AtomicUsize::new(0)))
+        .unwrap();
+    let conn = pool.get().unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(2, pool.state().idle_connections);
+    assert_eq!(3, pool.0.manager.0.load(Ordering::SeqCst));
+
+    pool.invalidate_all();
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(2, pool.state().idle_connections);
+    assert_eq!(3, pool.state().connections);
+    assert_eq!(5, pool.0.manager.0.load(Ordering::SeqCst));
+
+    // the checked out connection is closed rather than reused
+    drop(conn);
+    assert_eq!(2, pool.state().idle_connections);
+    assert_eq!(2, pool.state().connections);
+}
+
+#[test]
+fn recycle_all() {
+    let reasons = Arc::new(Mutex::new(vec![]));
+    let pool = Pool::builder()
+        .max_size(2)
+        .event_handler(Box::new(ReleaseReasonHandler(reasons.clone())))
+        .build(CountingManager(AtomicUsize::new(0)))
+        .unwrap();
+    assert_eq!(2, pool.0.manager.0.load(Ordering::SeqCst));
+
+    let conn = pool.get().unwrap();
+    pool.recycle_all(20);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(3, pool.0.manager.0.load(Ordering::SeqCst));
+    assert_eq!(2, pool.state().connections);
+    assert_eq!(vec![ReleaseReason::Recycled], *reasons.lock());
+
+    // the checked out connection is replaced once it's returned
+    drop(conn);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(4, pool.0.manager.0.load(Ordering::SeqCst));
+    assert_eq!(2, pool.state().connections);
+    assert_eq!(2, reasons.lock().len());
+    assert!(pool.0.recycle.lock().is_none());
+}
+
+#[test]
+fn recycle_all_stays_within_max_size() {
+    struct SlowManager;
+
+    impl ManageConnection for SlowManager {
+        type Connection = FakeConnection;
+        type Error = Error;
+
+        fn connect(&self) -> Result<FakeConnection, Error> {
+            thread::sleep(Duration::from_millis(200));
+            Ok(FakeConnection(true))
+        }
+
+        fn is_valid(&self, _: &mut FakeConnection) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn has_broken(&self, _: &mut FakeConnection) -> bool {
+            false
+        }
+    }
+
+    let pool = Pool::builder().max_size(2).build(SlowManager).unwrap();
+
+    pool.recycle_all(1000);
+    while pool.0.pending_conns() == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    // the connection being replaced can't be checked out, so the second
+    // checkout waits for its replacement
+    let conn1 = pool.get().unwrap();
+    let conn2 = pool.get().unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(2, pool.state().connections);
+
+    drop(conn1);
+    drop(conn2);
+    thread::sleep(Duration::from_millis(1500));
+    assert_eq!(2, pool.state().connections);
+    assert!(pool.0.recycle.lock().is_none());
+}
+
+#[test]
+fn recycle_all_synchronous() {
+    let pool = Pool::builder()
+        .max_size(2)
+        .synchronous(true)
+        .build(OkManager)
+        .unwrap();
+
+    let conn1 = pool.get().unwrap();
+    let conn2 = pool.get().unwrap();
+    let id2 = PooledConnection::id(&conn2);
+    pool.recycle_all(1);
+    assert_eq!(2, pool.state().connections);
+
+    // connections are closed as they're returned, at up to the rate
+    drop(conn1);
+    drop(conn2);
+    assert_eq!(1, pool.state().connections);
+
+    // and replaced when needed
+    let conn1 = pool.get().unwrap();
+    let conn2 = pool.get().unwrap();
+    assert_eq!(id2, PooledConnection::id(&conn1));
+    assert_ne!(id2, PooledConnection::id(&conn2));
+    assert_eq!(2, pool.state().connections);
+}
+
+#[test]
+fn with() {
+    let pool = Pool::builder()
+        .max_size(1)
+        .synchronous(true)
+        .build(OkManager)
+        .unwrap();
+
+    assert!(pool.with(|conn| Ok::<_, Error>(conn.0), |_| true).unwrap());
+    assert_eq!(1, pool.state().idle_connections);
+
+    let r = pool.with(|_| Err::<(), _>(Error), |_| false);
+    assert!(matches!(r, Err(WithError::Inner(Error))));
+    assert_eq!(1, pool.state().connections);
+
+    let r = pool.with(|_| Err::<(), _>(Error), |_| true);
+    assert!(matches!(r, Err(WithError::Inner(Error))));
+    assert_eq!(0, pool.state().connections);
+
+    let r = panic::catch_unwind(AssertUnwindSafe(|| {
+        pool.with(|_| -> Result<(), Error> { panic!("oh no") }, |_| false)
+    }));
+    assert!(r.is_err());
+    assert_eq!(0, pool.state().connections);
+
+    let conn = pool.get().unwrap();
+    let r = pool.with_timeout(
+        Duration::from_millis(100),
+        |_| Ok::<_, Error>(()),
+        |_| false,
+    );
+    assert!(matches!(r, Err(WithError::Pool(_))));
+    drop(conn);
+}
+
+#[test]
+fn get_many() {
+    let pool = Pool::builder().max_size(2).build(OkManager).unwrap();
+
+    let conns = pool.get_many(2, Duration::from_secs(1)).unwrap();
+    assert_eq!(2, conns.len());
+    assert_eq!(0, pool.state().idle_connections);
+    drop(conns);
+
+    // holding one connection means the pair can never be reserved
+    let conn = pool.get().unwrap();
+    assert!(pool.get_many(2, Duration::from_millis(100)).is_err());
+    assert_eq!(1, pool.state().idle_connections);
+    drop(conn);
+
+    let barrier = Arc::new(Barrier::new(4));
+    let handles = (0..4)
+        .map(|_| {
+            let pool = pool.clone();
+            let barrier = barrier.clone();
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..20 {
+                    let conns = pool.get_many(2, Duration::from_secs(5)).unwrap();
+                    assert_eq!(2, conns.len());
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn get_many_checkout_failure() {
+    static CHECKOUTS: AtomicUsize = AtomicUsize::new(0);
+    static CHECKINS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Debug)]
+    struct Customizer;
+
+    impl CustomizeConnection<FakeConnection, Error> for Customizer {
+        fn on_checkout(&self, _: &mut FakeConnection) -> Result<(), Error> {
+            if CHECKOUTS.fetch_add(1, Ordering::SeqCst) == 1 {
+                Err(Error)
+            } else {
+                Ok(())
+            }
+        }
+
+        fn on_checkin(&self, _: &mut FakeConnection) -> Result<(), Error> {
+            CHECKINS.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    let pool = Pool::builder()
+        .max_size(3)
+        .synchronous(true)
+        .connection_customizer(Box::new(Customizer))
+        .build(OkManager)
+        .unwrap();
+
+    // the connection prepared for the failed batch is reset and returned to
+    // the pool without counting as a use
+    let conns = pool.get_many(2, Duration::from_secs(1)).unwrap();
+    assert_eq!(1, CHECKINS.load(Ordering::SeqCst));
+    assert_eq!(4, CHECKOUTS.load(Ordering::SeqCst));
+    for conn in &conns {
+        assert_eq!(1, PooledConnection::use_count(conn));
+    }
+    assert_eq!(2, pool.state().connections);
+}
+
+#[test]
+#[should_panic(expected = "n must be no larger than max_size")]
+fn get_many_too_many() {
+    let pool = Pool::builder().max_size(2).build(OkManager).unwrap();
+    let _ = pool.get_many(3, Duration::from_secs(1));
+}
+
+#[test]
+fn detect_reentrant_checkout() {
+    let pool = Pool::builder()
+        .max_size(2)
+        .detect_reentrant_checkout(true)
+        .build(OkManager)
+        .unwrap();
+
+    let conn1 = pool.get().unwrap();
+    let conn2 = pool.get().unwrap();
+    drop(conn1);
+
+    // connections count against the thread which checked them out
+    thread::spawn(move || drop(conn2)).join().unwrap();
+
+    let conns = pool.get_many(2, Duration::from_secs(1)).unwrap();
+    drop(conns);
+
+    // another thread's connections don't count
+    let conns = pool.get_many(2, Duration::from_secs(1)).unwrap();
+    let pool2 = pool.clone();
+    let handle = thread::spawn(move || pool2.get().unwrap());
+    drop(conns);
+    drop(handle.join().unwrap());
+
+    let conn1 = pool.get().unwrap();
+    let conn2 = pool.get().unwrap();
+    let start = Instant::now();
+    let err = pool.get_timeout(Duration::from_secs(10)).err().unwrap();
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(
+        "reentrant checkout would deadlock: the current thread already holds 2 of the \
+         pool's 2 connections, so checking out 1 more would wait forever",
+        err.to_string()
+    );
+
+    // connections moved to another thread still count against this one
+    let (tx, rx) = mpsc::channel();
+    let handle = thread::spawn(move || {
+        rx.recv().unwrap();
+        drop((conn1, conn2));
+    });
+    assert!(pool.get_timeout(Duration::from_secs(10)).is_err());
+    tx.send(()).unwrap();
+    handle.join().unwrap();
+    assert_eq!(2, pool.state().idle_connections);
+}
+
+#[test]
+fn thread_cache() {
+    let pool = Pool::builder()
+        .max_size(2)
+        .thread_cache(true)
+        .build(OkManager)
+        .unwrap();
+
+    let conn = pool.get().unwrap();
+    let id = PooledConnection::id(&conn);
+    drop(conn);
+
+    // other threads don't see the cached connection while there are others
+    let pool2 = pool.clone();
+    let other = thread::spawn(move || {
+        let conn = pool2.try_get().unwrap();
+        PooledConnection::id(&conn)
+    })
+    .join()
+    .unwrap();
+    assert_ne!(id, other);
+
+    let conn = pool.get().unwrap();
+    assert_eq!(id, PooledConnection::id(&conn));
+    // the exited thread's cached connection still counts as idle
+    assert_eq!(1, pool.state().idle_connections);
+    drop(conn);
+    assert_eq!(2, pool.state().idle_connections);
+
+    // waiters pick up connections cached by other threads
+    let conns = pool.get_many(2, Duration::from_secs(1)).unwrap();
+    drop(conns);
+
+    let pool = Pool::builder()
+        .max_size(1)
+        .thread_cache(true)
+        .build(OkManager)
+        .unwrap();
+    drop(pool.get().unwrap());
+
+    let pool2 = pool.clone();
+    thread::spawn(move || drop(pool2.get_timeout(Duration::from_secs(1)).unwrap()))
+        .join()
+        .unwrap();
+    drop(pool.get_timeout(Duration::from_secs(1)).unwrap());
+    assert_eq!(1, pool.state().connections);
+}
+
+#[test]
+fn thread_cache_visible() {
+    let pool = Pool::builder()
+        .max_size(1)
+        .thread_cache(true)
+        .build(OkManager)
+        .unwrap();
+    drop(pool.get().unwrap());
+
+    // other threads fall back to cached connections when there are no others
+    let pool2 = pool.clone();
+    thread::spawn(move || assert!(pool2.try_get().is_some()))
+        .join()
+        .unwrap();
+
+    let manager = BalancingManager::new(vec![OkManager, OkManager], Strategy::RoundRobin);
+    let pool = Pool::builder()
+        .max_size(2)
+        .thread_cache(true)
+        .build(manager)
+        .unwrap();
+    let conn = pool.get().unwrap();
+    let endpoint = BalancedConnection::endpoint(&conn);
+    drop(conn);
+    assert_eq!(1, pool.0.cache.as_ref().unwrap().len());
+    assert_eq!(1, pool.endpoint_states()[endpoint].idle_connections);
+
+    // cached connections can be reclaimed for other keys
+    let pool = KeyedPool::builder()
+        .max_connections(1)
+        .key_config(|_, builder| builder.thread_cache(true))
+        .build(|key: &u32| KeyManager(*key));
+    drop(pool.get(&1).unwrap());
+    let conn = pool.get_timeout(&2, Duration::from_secs(1)).unwrap();
+    assert_eq!(2, *conn);
+}
+
+#[test]
+fn thread_cache_reaped() {
+    let pool = Pool::builder()
+        .max_size(1)
+        .min_idle(Some(0))
+        .max_lifetime(Some(Duration::from_millis(100)))
+        .automatic_reaping(false)
+        .thread_cache(true)
+        .build(OkManager)
+        .unwrap();
+
+    drop(pool.get().unwrap());
+    assert_eq!(1, pool.state().idle_connections);
+
+    thread::sleep(Duration::from_millis(200));
+    let report = pool.run_maintenance();
+    assert_eq!(1, report.reaped_connections);
+    assert_eq!(0, pool.state().connections);
+}
+
+#[test]
+fn thread_cache_validated() {
+    let pool = Pool::builder()
+        .max_size(1)
+        .min_idle(Some(0))
+        .automatic_reaping(false)
+        .thread_cache(true)
+        .build(ToggleManager(AtomicBool::new(true)))
+        .unwrap();
+
+    drop(pool.get().unwrap());
+    assert_eq!(1, pool.state().idle_connections);
+
+    pool.0.manager.0.store(false, Ordering::SeqCst);
+    let report = pool.run_maintenance();
+    assert_eq!(1, report.invalid_connections);
+    assert_eq!(0, pool.state().connections);
+}